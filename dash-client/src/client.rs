@@ -1,8 +1,10 @@
 use crate::{config, network, transaction::TransactionManager};
 use dash_common::crypto::publickey_to_base64;
 
-use anyhow::Result;
-use log::trace;
+use anyhow::{anyhow, Result};
+use hotstuff_rs::types::DalekKeypair;
+use log::{info, trace};
+use tokio_util::sync::CancellationToken;
 
 const PENDING_TRANSACTIONS: u64 = 10;
 pub struct Client {
@@ -13,18 +15,27 @@ pub struct Client {
 impl Client {
     pub fn new(config: config::Config) -> Result<Self> {
         trace!("new client with config: {:?}", config);
-        let quorum = config.node_addrs.len() as u64 / 3 * 2 + 1;
-        let network = network::Network::new(config.node_addrs)?;
+        if config.validators.is_empty() {
+            return Err(anyhow!(
+                "no validators configured, receipts cannot be verified"
+            ));
+        }
+        let validator_set = config.validator_set();
         let keypair = config.keypair.unwrap();
-        let pubkey = keypair.public.to_bytes();
+        let network = network::Network::new(
+            config.node_addrs,
+            config.chain_id,
+            DalekKeypair::from_bytes(&keypair.to_bytes())?,
+        )?;
         Ok(Self {
             network,
-            transaction_manager: TransactionManager::new(quorum, pubkey),
+            transaction_manager: TransactionManager::new(config.chain_id, validator_set, keypair),
         })
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        loop {
+    /// Keeps sending transactions until `shutdown` is cancelled.
+    pub async fn run(&mut self, shutdown: CancellationToken) -> Result<()> {
+        while !shutdown.is_cancelled() {
             if self.transaction_manager.pending_sum() < PENDING_TRANSACTIONS {
                 trace!(
                     "pending transaction: {}, so send new transaction",
@@ -33,6 +44,10 @@ impl Client {
                 let transaction = self.transaction_manager.generate_transaction()?;
                 self.network.send_transaction(transaction).await?;
             }
+            for transaction in self.transaction_manager.due_retries() {
+                trace!("submit busy transaction again");
+                self.network.send_transaction(transaction).await?;
+            }
 
             if let Some(receipt) = self.network.receive_transaction_receipt().await? {
                 trace!(
@@ -42,5 +57,11 @@ impl Client {
                 self.transaction_manager.collect_commit(receipt)?;
             }
         }
+        info!(
+            "shutting down with {} pending transactions",
+            self.transaction_manager.pending_sum()
+        );
+        self.network.shutdown().await;
+        Ok(())
     }
 }
@@ -1,22 +1,38 @@
-use dash_common::crypto::keypair_from_pem;
+use dash_common::crypto::{self, keypair_from_pem};
 
 use std::env::current_exe;
 use std::net::SocketAddr;
 use std::path::Path;
 
 use anyhow::{anyhow, Ok, Result};
-use hotstuff_rs::types::DalekKeypair;
+use hotstuff_rs::types::{ChainID, DalekKeypair, Power, PublicKeyBytes, ValidatorSet};
 use log::info;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::fs::read_to_string;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub node_addrs: Vec<SocketAddr>,
+    /// Chain ID of the nodes, transactions are bound to it.
+    #[serde(default = "default_chain_id")]
+    pub chain_id: ChainID,
+    /// Validators of the chain, only receipts signed by them count and commit proofs are
+    /// checked against them.
+    pub validators: Vec<ValidatorConfig>,
     #[serde(skip)]
     pub keypair: Option<DalekKeypair>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidatorConfig {
+    /// Base64 public key.
+    #[serde(deserialize_with = "parse_pubkey", serialize_with = "serialize_pubkey")]
+    pub public_key: PublicKeyBytes,
+    /// Voting power, 1 when omitted.
+    #[serde(default = "default_power")]
+    pub power: Power,
+}
+
 impl Config {
     pub async fn new() -> Result<Self> {
         let config_dir = current_exe()?.parent().unwrap().join("config");
@@ -45,4 +61,35 @@ impl Config {
 
         Ok(config)
     }
+
+    pub fn validator_set(&self) -> ValidatorSet {
+        let mut validator_set = ValidatorSet::new();
+        for validator in &self.validators {
+            validator_set.put(&validator.public_key, validator.power);
+        }
+        validator_set
+    }
+}
+
+fn default_chain_id() -> ChainID {
+    1
+}
+
+fn default_power() -> Power {
+    1
+}
+
+fn parse_pubkey<'de, D>(d: D) -> Result<PublicKeyBytes, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pubkey: String = Deserialize::deserialize(d)?;
+    crypto::publickey_from_base64(&pubkey).map_err(serde::de::Error::custom)
+}
+
+fn serialize_pubkey<S>(key: &PublicKeyBytes, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    Serialize::serialize(&crypto::publickey_to_base64(*key), s)
 }
@@ -1,15 +1,33 @@
 use dash_client::{client::Client, config::Config};
 
+use std::io;
+
 use anyhow::Result;
 use clap::Arg;
-use log::LevelFilter;
+use log::{info, LevelFilter};
 use simple_logger::SimpleLogger;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     init_logger()?;
     let config = init_config().await?;
-    Client::new(config)?.run().await?;
+    let shutdown = CancellationToken::new();
+    let mut terminate = signal(SignalKind::terminate())?;
+    let token = shutdown.clone();
+    tokio::spawn(async move {
+        let signal: io::Result<_> = tokio::select! {
+            res = tokio::signal::ctrl_c() => res.map(|()| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        };
+        match signal {
+            Ok(signal) => info!("received {}, shutting down", signal),
+            Err(e) => info!("cannot listen for signals, shutting down: {}", e),
+        }
+        token.cancel();
+    });
+    Client::new(config)?.run(shutdown).await?;
     Ok(())
 }
 
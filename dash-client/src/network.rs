@@ -1,31 +1,75 @@
-use dash_common::{NewTransactionRequest, TransactionReceipt};
-use dash_network::client::Client;
+use dash_common::{
+    proof::HeaderRequest, subscription::Subscription, ClientMessage, Login, NewTransactionRequest,
+    NodeMessage, TransactionReceipt,
+};
+use dash_network::{client::Client, stats::Stats, typed::typed, Handle, Options};
 
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use anyhow::Result;
-use borsh::{BorshDeserialize, BorshSerialize};
-use bytes::Bytes;
-use tokio::sync::mpsc::{channel, error::TryRecvError, Receiver, Sender};
+use hotstuff_rs::types::{ChainID, DalekKeypair};
+use log::{debug, info, warn};
+use tokio::{
+    sync::mpsc::{channel, error::TryRecvError, Receiver, Sender},
+    time,
+};
+
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Period of checking for reconnected nodes, which are logged in again.
+const RECONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Network {
     // peers: Vec<SocketAddr>,
-    tx_sender: Sender<NewTransactionRequest>,
+    tx_sender: Sender<ClientMessage>,
     rx_receiver: Receiver<TransactionReceipt>,
+    notification_receiver: Receiver<NodeMessage>,
+    handle: Handle,
 }
 
 impl Network {
-    pub fn new(peers: Vec<SocketAddr>) -> Result<Self> {
-        let (tx_sender, rx_receiver) = spawn_main_worker_thread(peers)?;
-        Ok(Self {
-            // peers,
-            tx_sender,
-            rx_receiver,
-        })
+    /// Logs in to every node with `keypair`, so that receipts reach this client across
+    /// reconnects.
+    pub fn new(peers: Vec<SocketAddr>, chain_id: ChainID, keypair: DalekKeypair) -> Result<Self> {
+        spawn_main_worker_thread(peers, chain_id, keypair)
     }
 
     pub async fn send_transaction(&self, transaction: NewTransactionRequest) -> Result<()> {
-        Ok(self.tx_sender.send(transaction).await?)
+        Ok(self
+            .tx_sender
+            .send(ClientMessage::Transaction(transaction))
+            .await?)
+    }
+
+    /// Subscribes to a feed of the committed chain. A single feed is enough, the first node
+    /// serves it.
+    pub async fn subscribe(&self, subscription: Subscription) -> Result<()> {
+        Ok(self
+            .tx_sender
+            .send(ClientMessage::Subscribe(subscription))
+            .await?)
+    }
+
+    /// Asks the first node for committed headers, they arrive as a notification.
+    pub async fn request_headers(&self, request: HeaderRequest) -> Result<()> {
+        Ok(self.tx_sender.send(ClientMessage::Headers(request)).await?)
+    }
+
+    /// Waits for the next message of the subscribed feeds or headers asked for, `None` once
+    /// the network is shut down.
+    pub async fn receive_notification(&mut self) -> Option<NodeMessage> {
+        self.notification_receiver.recv().await
+    }
+
+    /// Traffic statistics of the connection to each node.
+    pub fn stats(&self) -> &Stats {
+        self.handle.stats()
+    }
+
+    /// Closes the connections to the nodes, receipts still in flight are lost.
+    pub async fn shutdown(&self) {
+        self.handle.shutdown().await;
     }
 
     pub async fn receive_transaction_receipt(&mut self) -> Result<Option<TransactionReceipt>> {
@@ -39,27 +83,82 @@ impl Network {
 
 fn spawn_main_worker_thread(
     peers: Vec<SocketAddr>,
-) -> Result<(Sender<NewTransactionRequest>, Receiver<TransactionReceipt>)> {
-    let (tx_sender, mut tx_receiver) = channel::<NewTransactionRequest>(1000);
+    chain_id: ChainID,
+    keypair: DalekKeypair,
+) -> Result<Network> {
+    let (tx_sender, mut tx_receiver) = channel::<ClientMessage>(1000);
     let (rx_sender, rx_receiver) = channel(1000);
+    let (notification_sender, notification_receiver) = channel(1000);
+    let (channel, handle) = Client::spawn(Options::default());
+    let stats = handle.stats().clone();
+    let ret_handle = handle.clone();
 
     tokio::spawn(async move {
-        let (sender, mut receiver) = Client::spawn();
+        let (sender, mut receiver) = typed::<ClientMessage, NodeMessage>(channel);
+        let mut stats_log = time::interval(STATS_LOG_INTERVAL);
+        let mut reconnect_check = time::interval(RECONNECT_CHECK_INTERVAL);
+        let mut connected = HashSet::new();
+        // Connections are opened on the first message, the login is it.
+        let login = ClientMessage::Login(Login::new(&keypair, chain_id));
+        for peer in peers.iter() {
+            if let Err(e) = sender.send(*peer, &login).await {
+                warn!("Send login to {} failed: {}", peer, e);
+            }
+        }
         loop {
             tokio::select! {
                 Some(request) = tx_receiver.recv() => {
+                    let peers = match request {
+                        ClientMessage::Subscribe(_) => &peers[..1],
+                        _ => &peers[..],
+                    };
+                    for peer in peers {
+                        if let Err(e) = sender.send(*peer, &request).await {
+                            warn!("Send transaction to {} failed: {}", peer, e);
+                        }
+                    }
+                }
+                _ = reconnect_check.tick() => {
                     for peer in peers.iter() {
-                        let data = Bytes::from(request.try_to_vec().unwrap());
-                        sender.send((*peer, data)).await.unwrap();
+                        if !stats.peer(peer).is_some_and(|stats| stats.connected) {
+                            connected.remove(peer);
+                        } else if connected.insert(*peer) {
+                            debug!("connected to {}, log in", peer);
+                            let login = ClientMessage::Login(Login::new(&keypair, chain_id));
+                            if let Err(e) = sender.send(*peer, &login).await {
+                                warn!("Send login to {} failed: {}", peer, e);
+                            }
+                        }
+                    }
+                }
+                message = receiver.recv() => {
+                    match message {
+                        Some((_, Ok(NodeMessage::Receipt(receipt)))) => {
+                            rx_sender.send(receipt).await.unwrap()
+                        }
+                        Some((_, Ok(notification))) => {
+                            if notification_sender.send(notification).await.is_err() {
+                                break;
+                            }
+                        }
+                        Some((addr, Err(e))) => warn!("Malformed message from {}: {}", addr, e),
+                        // The client is shut down.
+                        None => break,
                     }
                 }
-                Some((_addr, msg_bytes)) = receiver.recv() => {
-                    let trans =
-                        TransactionReceipt::deserialize_reader(&mut msg_bytes.as_ref()).unwrap();
-                    rx_sender.send(trans).await.unwrap();
+                _ = stats_log.tick() => {
+                    for (addr, stats) in stats.peers() {
+                        info!("node {}: {}", addr, stats);
+                    }
                 }
             }
         }
     });
-    Ok((tx_sender, rx_receiver))
+    Ok(Network {
+        // peers,
+        tx_sender,
+        rx_receiver,
+        notification_receiver,
+        handle: ret_handle,
+    })
 }
@@ -1,54 +1,78 @@
 use dash_common::{
-    crypto::publickey_to_base64, NewTransactionRequest, TransactionHash, TransactionReceipt,
+    crypto::publickey_to_base64, NewTransactionRequest, TransactionData, TransactionHash,
+    TransactionReceipt, TransactionResult,
 };
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use chrono::{DateTime, Local};
-use hotstuff_rs::types::PublicKeyBytes;
+use hotstuff_rs::types::{
+    ChainID, DalekKeypair, PublicKeyBytes, QuorumCertificate, TotalPower, ValidatorSet,
+};
 use log::{debug, trace, warn};
 use rand::{thread_rng, Rng};
-use sha2::{Digest, Sha256};
 
 type TransactionTimestamp = (DateTime<Local>, DateTime<Local>);
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
+struct PendingTransaction {
+    request: NewTransactionRequest,
+    start: DateTime<Local>,
+    /// Nodes that reported the commit.
+    receiptors: HashSet<PublicKeyBytes>,
+}
+
 pub struct TransactionManager {
-    quorum: u64,
+    /// Power of the validators whose receipts commit a transaction without a proof.
+    quorum: TotalPower,
+    chain_id: ChainID,
+    /// Receipts must be signed by these validators to be counted, and commit proofs are
+    /// checked against them.
+    validator_set: ValidatorSet,
     sequence_number: u64,
-    pending_transactions: HashMap<TransactionHash, (DateTime<Local>, u64)>,
+    pending_transactions: HashMap<TransactionHash, PendingTransaction>,
     commited_transactions: HashMap<TransactionHash, TransactionTimestamp>,
-    pubkey: PublicKeyBytes,
+    /// Transactions a node was too busy to take, with when to submit them again.
+    retries: HashMap<TransactionHash, Instant>,
+    keypair: DalekKeypair,
 }
 
 impl TransactionManager {
-    pub fn new(quorum: u64, pubkey: PublicKeyBytes) -> Self {
+    pub fn new(chain_id: ChainID, validator_set: ValidatorSet, keypair: DalekKeypair) -> Self {
+        let quorum = QuorumCertificate::quorum(validator_set.total_power());
         debug!(
-            "new transaction manager with quorum: {}, pubkey: {}",
+            "new transaction manager with quorum: {}, chain: {}, pubkey: {}",
             quorum,
-            publickey_to_base64(pubkey)
+            chain_id,
+            publickey_to_base64(keypair.public.to_bytes())
         );
         Self {
             quorum,
+            chain_id,
+            validator_set,
             sequence_number: Default::default(),
             pending_transactions: Default::default(),
             commited_transactions: Default::default(),
-            pubkey,
+            retries: Default::default(),
+            keypair,
         }
     }
 
     pub fn generate_transaction(&mut self) -> Result<NewTransactionRequest> {
         let data = generate_random_bytes(128);
-        let hash: TransactionHash = Sha256::digest(&data).into();
-        let transaction = NewTransactionRequest {
-            requester: self.pubkey,
-            hash,
-            data,
-        };
+        let transaction =
+            NewTransactionRequest::new(&self.keypair, self.chain_id, TransactionData::App(data));
         self.sequence_number = self.sequence_number.wrapping_add(1);
-        self.pending_transactions
-            .insert(transaction.hash, (Local::now(), 0));
+        self.pending_transactions.insert(
+            transaction.hash,
+            PendingTransaction {
+                request: transaction.clone(),
+                start: Local::now(),
+                receiptors: Default::default(),
+            },
+        );
         trace!(
             "generate new transaction: {:?}, pending: {}",
             transaction,
@@ -59,15 +83,72 @@ impl TransactionManager {
 
     pub fn collect_commit(&mut self, receipt: TransactionReceipt) -> Result<()> {
         trace!("collect commit: {:?}", receipt);
+        if self.validator_set.power(&receipt.receiptor).is_none() {
+            warn!(
+                "Receipt from {}, which is not a validator",
+                publickey_to_base64(receipt.receiptor)
+            );
+            return Ok(());
+        }
+        if let Err(e) = receipt.verify(self.chain_id) {
+            warn!(
+                "Receipt from {} dropped: {}",
+                publickey_to_base64(receipt.receiptor),
+                e
+            );
+            return Ok(());
+        }
+        match receipt.result {
+            TransactionResult::Commited => (),
+            TransactionResult::Unaccepted { reason } => {
+                warn!(
+                    "Transaction rejected by {}: {}",
+                    publickey_to_base64(receipt.receiptor),
+                    reason
+                );
+                return Ok(());
+            }
+            TransactionResult::Busy { retry_after_ms } => {
+                debug!(
+                    "{} is busy, retry in {} ms",
+                    publickey_to_base64(receipt.receiptor),
+                    retry_after_ms
+                );
+                let retry_at = Instant::now() + Duration::from_millis(retry_after_ms);
+                let entry = self.retries.entry(receipt.hash).or_insert(retry_at);
+                *entry = (*entry).max(retry_at);
+                return Ok(());
+            }
+        }
+        // A valid commit proof settles it, otherwise a quorum of receipts is waited for.
+        let proven = match &receipt.proof {
+            Some(proof) => match proof.verify(self.chain_id, &self.validator_set, &receipt.hash) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!(
+                        "Commit proof from {} not taken: {}",
+                        publickey_to_base64(receipt.receiptor),
+                        e
+                    );
+                    false
+                }
+            },
+            None => false,
+        };
         match self.pending_transactions.entry(receipt.hash) {
             Entry::Occupied(mut entry) => {
-                let (_, commited_sum) = *entry.get();
-                if commited_sum >= self.quorum {
-                    let (start, _) = entry.remove();
+                entry.get_mut().receiptors.insert(receipt.receiptor);
+                let power: TotalPower = entry
+                    .get()
+                    .receiptors
+                    .iter()
+                    .filter_map(|receiptor| self.validator_set.power(receiptor))
+                    .map(|&power| power as TotalPower)
+                    .sum();
+                if proven || power >= self.quorum {
+                    let pending = entry.remove();
                     self.commited_transactions
-                        .insert(receipt.hash, (start, Local::now()));
-                } else {
-                    entry.get_mut().1 += 1;
+                        .insert(receipt.hash, (pending.start, Local::now()));
                 }
                 trace!(
                     "collect commit: {:?}, pending: {}, commited: {}",
@@ -83,6 +164,26 @@ impl TransactionManager {
         Ok(())
     }
 
+    /// Pending transactions due to be submitted again after a busy reply. Nodes that took
+    /// them already answer the duplicate with the same receipt.
+    pub fn due_retries(&mut self) -> Vec<NewTransactionRequest> {
+        let now = Instant::now();
+        let due: Vec<_> = self
+            .retries
+            .iter()
+            .filter(|(_, retry_at)| **retry_at <= now)
+            .map(|(hash, _)| *hash)
+            .collect();
+        due.iter()
+            .filter_map(|hash| {
+                self.retries.remove(hash);
+                self.pending_transactions
+                    .get(hash)
+                    .map(|pending| pending.request.clone())
+            })
+            .collect()
+    }
+
     pub fn pending_sum(&self) -> u64 {
         self.pending_transactions.len() as u64
     }
@@ -1,8 +1,8 @@
 use anyhow::Result;
 use base64::{engine::general_purpose, Engine};
 use ed25519::pkcs8::{DecodePrivateKey, EncodePrivateKey, KeypairBytes};
-use ed25519_dalek::{PublicKey, SecretKey};
-use hotstuff_rs::types::{DalekKeypair, PublicKeyBytes};
+use ed25519_dalek::{PublicKey, SecretKey, Signature, Signer, Verifier};
+use hotstuff_rs::types::{DalekKeypair, PublicKeyBytes, SignatureBytes};
 use rand::rngs::OsRng;
 
 pub fn generate_keypair() -> DalekKeypair {
@@ -45,6 +45,21 @@ pub fn keypair_from_bytes(kpb: KeypairBytes) -> Result<DalekKeypair> {
     Ok(DalekKeypair { secret, public })
 }
 
+pub fn sign(keypair: &DalekKeypair, msg: &[u8]) -> SignatureBytes {
+    keypair.sign(msg).to_bytes()
+}
+
+/// Whether `signature` is a signature of `msg` by `pubkey`, false for malformed keys.
+pub fn verify(pubkey: &PublicKeyBytes, msg: &[u8], signature: &SignatureBytes) -> bool {
+    let Ok(pubkey) = PublicKey::from_bytes(pubkey) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_bytes(signature) else {
+        return false;
+    };
+    pubkey.verify(msg, &signature).is_ok()
+}
+
 #[cfg(test)]
 mod crypto_tests {
     use super::*;
@@ -59,4 +74,14 @@ mod crypto_tests {
         let keypair_parsed = keypair_from_pem(&sk_pem).unwrap();
         assert_eq!(keypair_parsed.public.to_bytes(), pubkeybytes);
     }
+
+    #[test]
+    fn sign_test() {
+        let keypair = generate_keypair();
+        let pubkey = keypair.public.to_bytes();
+        let signature = sign(&keypair, b"hello");
+        assert!(verify(&pubkey, b"hello", &signature));
+        assert!(!verify(&pubkey, b"world", &signature));
+        assert!(!verify(&[0; 32], b"hello", &signature));
+    }
 }
@@ -0,0 +1,181 @@
+use crate::crypto::{self, publickey_to_base64};
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
+use hotstuff_rs::types::{
+    ChainID, DalekKeypair, Power, PublicKeyBytes, QuorumCertificate, SignatureBytes, TotalPower,
+    ValidatorSet, ValidatorSetUpdates,
+};
+
+/// App state key of the number of governance transactions applied so far.
+pub const GOVERNANCE_NONCE_KEY: &[u8] = b"governance/nonce";
+
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum ValidatorChange {
+    /// Adds a validator or changes its power.
+    Put {
+        public_key: PublicKeyBytes,
+        power: Power,
+    },
+    Remove {
+        public_key: PublicKeyBytes,
+    },
+}
+
+/// A change of the validator set. It takes effect once signed by current validators holding
+/// a quorum of the voting power.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct GovernanceTransaction {
+    pub chain_id: ChainID,
+    /// Number of governance transactions applied before this one, so that a signed change
+    /// cannot be replayed.
+    pub nonce: u64,
+    pub changes: Vec<ValidatorChange>,
+    pub signatures: Vec<(PublicKeyBytes, SignatureBytes)>,
+}
+
+impl GovernanceTransaction {
+    pub fn new(chain_id: ChainID, nonce: u64, changes: Vec<ValidatorChange>) -> Self {
+        Self {
+            chain_id,
+            nonce,
+            changes,
+            signatures: vec![],
+        }
+    }
+
+    /// Adds the signature of `keypair`, replacing a previous one of the same key.
+    pub fn sign(&mut self, keypair: &DalekKeypair) {
+        let public_key = keypair.public.to_bytes();
+        let signature = crypto::sign(keypair, &self.signed_bytes());
+        self.signatures.retain(|(key, _)| *key != public_key);
+        self.signatures.push((public_key, signature));
+    }
+
+    /// Checks the transaction against the chain, the current validator set and governance
+    /// nonce, returns the updates to apply.
+    pub fn verify(
+        &self,
+        validator_set: &ValidatorSet,
+        chain_id: ChainID,
+        nonce: u64,
+    ) -> Result<ValidatorSetUpdates> {
+        if self.chain_id != chain_id {
+            return Err(anyhow!(
+                "transaction for chain {}, this is chain {}",
+                self.chain_id,
+                chain_id
+            ));
+        }
+        if self.nonce != nonce {
+            return Err(anyhow!("nonce {} expected, got {}", nonce, self.nonce));
+        }
+        let bytes = self.signed_bytes();
+        let mut signers = HashSet::new();
+        let mut signed_power: TotalPower = 0;
+        for (key, signature) in &self.signatures {
+            let Some(power) = validator_set.power(key) else {
+                return Err(anyhow!("{} is not a validator", publickey_to_base64(*key)));
+            };
+            if !crypto::verify(key, &bytes, signature) {
+                return Err(anyhow!(
+                    "invalid signature of {}",
+                    publickey_to_base64(*key)
+                ));
+            }
+            if signers.insert(*key) {
+                signed_power += *power as TotalPower;
+            }
+        }
+        let total_power = validator_set
+            .validators_and_powers()
+            .iter()
+            .map(|(_, power)| *power as TotalPower)
+            .sum();
+        if signed_power < QuorumCertificate::quorum(total_power) {
+            return Err(anyhow!(
+                "signed by {} of {} voting power, short of a quorum",
+                signed_power,
+                total_power
+            ));
+        }
+
+        let updates = self.updates()?;
+        let mut updated = validator_set.clone();
+        updated.apply_updates(&updates);
+        if updated.validators().len() == 0 {
+            return Err(anyhow!("the validator set would be empty"));
+        }
+        Ok(updates)
+    }
+
+    fn updates(&self) -> Result<ValidatorSetUpdates> {
+        let mut updates = ValidatorSetUpdates::new();
+        for change in &self.changes {
+            match change {
+                ValidatorChange::Put { power: 0, .. } => {
+                    return Err(anyhow!("validators must have a positive power"))
+                }
+                ValidatorChange::Put { public_key, power } => updates.insert(*public_key, *power),
+                ValidatorChange::Remove { public_key } => updates.delete(*public_key),
+            }
+        }
+        Ok(updates)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        (self.chain_id, self.nonce, &self.changes)
+            .try_to_vec()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod governance_tests {
+    use super::*;
+    use crate::crypto::generate_keypair;
+
+    #[test]
+    fn threshold_test() {
+        let keypairs: Vec<_> = (0..4).map(|_| generate_keypair()).collect();
+        let mut validator_set = ValidatorSet::new();
+        for keypair in &keypairs {
+            validator_set.put(&keypair.public.to_bytes(), 1);
+        }
+        let joining = generate_keypair().public.to_bytes();
+        let mut transaction = GovernanceTransaction::new(
+            1,
+            0,
+            vec![
+                ValidatorChange::Put {
+                    public_key: joining,
+                    power: 2,
+                },
+                ValidatorChange::Remove {
+                    public_key: keypairs[0].public.to_bytes(),
+                },
+            ],
+        );
+
+        // 2 of 4 is short of a quorum, signing twice does not count twice.
+        transaction.sign(&keypairs[0]);
+        transaction.sign(&keypairs[1]);
+        transaction.sign(&keypairs[1]);
+        assert!(transaction.verify(&validator_set, 1, 0).is_err());
+
+        transaction.sign(&keypairs[2]);
+        let updates = transaction.verify(&validator_set, 1, 0).unwrap();
+        assert_eq!(updates.get_insert(&joining), Some(&2));
+        assert!(updates.contains_delete(&keypairs[0].public.to_bytes()));
+        // Replays are rejected.
+        assert!(transaction.verify(&validator_set, 1, 1).is_err());
+        // So are transactions of another chain.
+        assert!(transaction.verify(&validator_set, 2, 0).is_err());
+
+        // Tampering voids the signatures.
+        transaction.changes.pop();
+        assert!(transaction.verify(&validator_set, 1, 0).is_err());
+    }
+}
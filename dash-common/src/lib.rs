@@ -1,4 +1,8 @@
 pub mod crypto;
+pub mod governance;
+pub mod light_client;
 pub mod message;
+pub mod proof;
+pub mod subscription;
 
 pub use message::*;
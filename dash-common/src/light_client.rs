@@ -0,0 +1,328 @@
+use crate::governance::{GovernanceTransaction, GOVERNANCE_NONCE_KEY};
+use crate::message::{NewTransactionRequest, TransactionData};
+use crate::proof::{
+    merkle_root, verify_commit, verify_qc, CommitProof, HeaderChain, InclusionProof,
+};
+
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::{anyhow, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
+use hotstuff_rs::types::{BlockHeight, ChainID, CryptoHash, ValidatorSet};
+
+/// Data hashes of the most recent committed blocks kept for inclusion proofs.
+const KEPT_HEADERS: usize = 100_000;
+
+/// Follows the committed chain from genesis without trusting the nodes it fetches headers
+/// from: every header must be certified by the validator set in charge, which is tracked
+/// through the governance transactions of the chain. Headers come with all the
+/// transactions of their block, checked against its data hash, so none of them can be
+/// left out.
+pub struct LightClient {
+    chain_id: ChainID,
+    /// Validator set once the highest verified block is committed.
+    validator_set: ValidatorSet,
+    /// Validator set that certifies the highest verified block, the one before its changes.
+    signers: ValidatorSet,
+    /// App state once the highest verified block is committed, only governance
+    /// transactions change it.
+    app_state: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Height and hash of the highest verified block.
+    tip: Option<(BlockHeight, CryptoHash)>,
+    /// Data hashes of the latest verified blocks, the last one at the tip.
+    data_hashes: VecDeque<CryptoHash>,
+}
+
+impl LightClient {
+    /// Starts at genesis with its validator set and app state.
+    pub fn new(
+        chain_id: ChainID,
+        validator_set: ValidatorSet,
+        app_state: BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> Self {
+        Self {
+            chain_id,
+            signers: validator_set.clone(),
+            validator_set,
+            app_state,
+            tip: None,
+            data_hashes: VecDeque::new(),
+        }
+    }
+
+    pub fn validator_set(&self) -> &ValidatorSet {
+        &self.validator_set
+    }
+
+    /// Height of the highest verified block, `None` before the first one.
+    pub fn height(&self) -> Option<BlockHeight> {
+        self.tip.map(|(height, _)| height)
+    }
+
+    /// Height to ask nodes for headers from.
+    pub fn next_height(&self) -> BlockHeight {
+        self.tip.map_or(0, |(height, _)| height + 1)
+    }
+
+    /// Moves on along headers fetched from any node. They must start right after the
+    /// highest verified block, nothing is taken unless all of them check out.
+    pub fn advance(&mut self, chain: &HeaderChain) -> Result<()> {
+        if chain.headers.is_empty() {
+            return Err(anyhow!("no headers"));
+        }
+        let mut next = Self {
+            chain_id: self.chain_id,
+            validator_set: self.validator_set.clone(),
+            signers: self.signers.clone(),
+            app_state: self.app_state.clone(),
+            tip: self.tip,
+            data_hashes: VecDeque::new(),
+        };
+        let mut data_hashes = vec![];
+        for committed in &chain.headers {
+            let header = &committed.header;
+            if header.height != next.next_height() {
+                return Err(anyhow!(
+                    "header at height {}, {} expected",
+                    header.height,
+                    next.next_height()
+                ));
+            }
+            match next.tip {
+                None if !header.justify.is_genesis_qc() => {
+                    return Err(anyhow!("the first block does not start from genesis"))
+                }
+                None => (),
+                Some((_, tip)) => {
+                    if header.justify.block != tip {
+                        return Err(anyhow!("block {} does not follow", header.height));
+                    }
+                    verify_qc(&header.justify, self.chain_id, &next.signers)?;
+                }
+            }
+            // Blocks are voted for by the validator set their parent leaves behind.
+            next.signers = next.validator_set.clone();
+            let hashes: Vec<_> = committed
+                .transactions
+                .iter()
+                .map(|request| request.hash)
+                .collect();
+            if merkle_root(&hashes) != header.data_hash {
+                return Err(anyhow!("transactions do not match block {}", header.height));
+            }
+            for request in &committed.transactions {
+                // The hash must be that of the data, or a governance transaction could pass
+                // for an app one.
+                request.verify(self.chain_id)?;
+                if let TransactionData::Governance(transaction) = &request.data {
+                    next.apply_governance(transaction)?;
+                }
+            }
+            next.tip = Some((header.height, header.hash()));
+            data_hashes.push(header.data_hash);
+        }
+        let (_, tip) = next.tip.unwrap();
+        verify_commit(
+            self.chain_id,
+            tip,
+            &chain.descendants,
+            &chain.qc,
+            (&next.signers, &next.validator_set),
+        )?;
+
+        self.validator_set = next.validator_set;
+        self.signers = next.signers;
+        self.app_state = next.app_state;
+        self.tip = next.tip;
+        self.data_hashes.extend(data_hashes);
+        while self.data_hashes.len() > KEPT_HEADERS {
+            self.data_hashes.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Applies a governance transaction the way the app does.
+    fn apply_governance(&mut self, transaction: &GovernanceTransaction) -> Result<()> {
+        let nonce = match self.app_state.get(GOVERNANCE_NONCE_KEY) {
+            Some(bytes) => u64::try_from_slice(bytes)?,
+            None => 0,
+        };
+        let updates = transaction.verify(&self.validator_set, self.chain_id, nonce)?;
+        self.validator_set.apply_updates(&updates);
+        self.app_state.insert(
+            GOVERNANCE_NONCE_KEY.to_vec(),
+            (nonce + 1).try_to_vec().unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Checks that `request` is in the verified block at `height`. Only the latest blocks
+    /// are kept, older ones can be checked with a [`CommitProof`] as long as the validator
+    /// set has not changed since.
+    pub fn verify_transaction(
+        &self,
+        height: BlockHeight,
+        request: &NewTransactionRequest,
+        inclusion: &InclusionProof,
+    ) -> Result<()> {
+        request.verify(self.chain_id)?;
+        let data_hash = self
+            .height()
+            .and_then(|tip| {
+                let back = tip.checked_sub(height)? as usize;
+                let index = self.data_hashes.len().checked_sub(back + 1)?;
+                self.data_hashes.get(index)
+            })
+            .ok_or_else(|| anyhow!("no verified block at height {}", height))?;
+        if inclusion.root(&request.hash) != Some(*data_hash) {
+            return Err(anyhow!("transaction not in the block"));
+        }
+        Ok(())
+    }
+
+    /// Checks a commit proof of a block the client may not have reached yet.
+    pub fn verify_commit_proof(
+        &self,
+        proof: &CommitProof,
+        request: &NewTransactionRequest,
+    ) -> Result<()> {
+        request.verify(self.chain_id)?;
+        proof.verify(self.chain_id, &self.validator_set, &request.hash)
+    }
+
+    /// An entry of the app state once the highest verified block is committed. It is
+    /// replayed from the genesis state through every transaction of the verified blocks,
+    /// all of them checked against their data hash.
+    pub fn app_state(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.app_state.get(key).cloned()
+    }
+}
+
+#[cfg(test)]
+mod light_client_tests {
+    use super::*;
+    use crate::crypto::{self, generate_keypair};
+    use crate::governance::{GovernanceTransaction, ValidatorChange};
+    use crate::proof::{merkle_root, BlockHeader, CommittedHeader};
+    use hotstuff_rs::types::{DalekKeypair, Phase, QuorumCertificate};
+
+    fn qc(keypair: &DalekKeypair, header: &BlockHeader, phase: Phase) -> QuorumCertificate {
+        let (view, block) = (header.height + 1, header.hash());
+        let bytes = (1u64, view, block, phase).try_to_vec().unwrap();
+        QuorumCertificate {
+            chain_id: 1,
+            view,
+            block,
+            phase,
+            signatures: vec![Some(crypto::sign(keypair, &bytes))],
+        }
+    }
+
+    #[test]
+    fn validator_change_test() {
+        let (old, new) = (generate_keypair(), generate_keypair());
+        let mut genesis = ValidatorSet::new();
+        genesis.put(&old.public.to_bytes(), 1);
+        let mut client = LightClient::new(1, genesis.clone(), BTreeMap::new());
+
+        // Height 1 hands the chain over from the old validator to the new one.
+        let mut transaction = GovernanceTransaction::new(
+            1,
+            0,
+            vec![
+                ValidatorChange::Put {
+                    public_key: new.public.to_bytes(),
+                    power: 1,
+                },
+                ValidatorChange::Remove {
+                    public_key: old.public.to_bytes(),
+                },
+            ],
+        );
+        transaction.sign(&old);
+        let request = NewTransactionRequest::new(
+            &generate_keypair(),
+            1,
+            TransactionData::Governance(transaction),
+        );
+        let app = NewTransactionRequest::new(&generate_keypair(), 1, TransactionData::App(vec![1]));
+        let leaves = [app.hash, request.hash];
+
+        let first = BlockHeader {
+            height: 0,
+            justify: QuorumCertificate::genesis_qc(),
+            data_hash: merkle_root(&[]),
+        };
+        let second = BlockHeader {
+            height: 1,
+            justify: qc(&old, &first, Phase::Generic),
+            data_hash: merkle_root(&leaves),
+        };
+        let third = BlockHeader {
+            height: 2,
+            justify: qc(&old, &second, Phase::Commit(2)),
+            data_hash: merkle_root(&[]),
+        };
+        let committed = |header: &BlockHeader| CommittedHeader {
+            header: header.clone(),
+            transactions: vec![],
+        };
+        let chain = HeaderChain {
+            headers: vec![
+                committed(&first),
+                CommittedHeader {
+                    header: second.clone(),
+                    transactions: vec![app.clone(), request.clone()],
+                },
+            ],
+            descendants: vec![],
+            qc: qc(&old, &second, Phase::Commit(2)),
+        };
+
+        // A node leaving out the change is caught, the block data does not add up.
+        let mut hidden = chain.clone();
+        hidden.headers[1].transactions.pop();
+        assert!(client.advance(&hidden).is_err());
+        // Neither can it pass the change off as an app transaction with the same hash.
+        let mut disguised = chain.clone();
+        disguised.headers[1].transactions[1].data = TransactionData::App(vec![2]);
+        assert!(client.advance(&disguised).is_err());
+        assert_eq!(client.height(), None);
+
+        let fourth = BlockHeader {
+            height: 3,
+            justify: qc(&new, &third, Phase::Generic),
+            data_hash: merkle_root(&[]),
+        };
+        let rest = HeaderChain {
+            headers: vec![committed(&third)],
+            descendants: vec![fourth.clone()],
+            qc: qc(&new, &fourth, Phase::Commit(4)),
+        };
+        assert!(client.advance(&rest).is_err());
+
+        client.advance(&chain).unwrap();
+        assert_eq!(client.height(), Some(1));
+        assert!(client
+            .validator_set()
+            .power(&new.public.to_bytes())
+            .is_some());
+        assert!(client
+            .validator_set()
+            .power(&old.public.to_bytes())
+            .is_none());
+        assert_eq!(
+            client.app_state(GOVERNANCE_NONCE_KEY),
+            Some(1u64.try_to_vec().unwrap())
+        );
+        client.advance(&rest).unwrap();
+        assert_eq!(client.next_height(), 3);
+        // Replayed headers are turned down.
+        assert!(client.advance(&chain).is_err());
+
+        let inclusion = InclusionProof::new(&leaves, 0).unwrap();
+        client.verify_transaction(1, &app, &inclusion).unwrap();
+        assert!(client.verify_transaction(2, &app, &inclusion).is_err());
+        assert!(client.verify_transaction(1, &request, &inclusion).is_err());
+    }
+}
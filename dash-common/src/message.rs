@@ -1,13 +1,182 @@
+use crate::crypto;
+use crate::governance::GovernanceTransaction;
+use crate::proof::{CommitProof, HeaderChain, HeaderRequest};
+use crate::subscription::{CommittedBlock, CommittedEvent, CommittedTransaction, Subscription};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
 use borsh::{BorshDeserialize, BorshSerialize};
-use hotstuff_rs::types::PublicKeyBytes;
+use hotstuff_rs::types::{ChainID, CryptoHasher, DalekKeypair, PublicKeyBytes, SignatureBytes};
+use sha2::Digest;
 
 pub type TransactionHash = [u8; 32];
 
+/// How far the timestamp of a login may be from the node's clock.
+const LOGIN_VALIDITY: Duration = Duration::from_secs(60);
+
+/// What clients send to nodes.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub enum ClientMessage {
+    Login(Login),
+    Transaction(NewTransactionRequest),
+    Subscribe(Subscription),
+    Headers(HeaderRequest),
+}
+
+/// What nodes send to clients.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub enum NodeMessage {
+    Receipt(TransactionReceipt),
+    Block(CommittedBlock),
+    Transaction(CommittedTransaction),
+    Event(CommittedEvent),
+    Headers(HeaderChain),
+}
+
+/// Identifies a client on a connection, so that its receipts follow it across reconnects.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct Login {
+    pub chain_id: ChainID,
+    pub public_key: PublicKeyBytes,
+    /// Seconds since the Unix epoch, so that a captured login goes stale.
+    pub timestamp: u64,
+    pub signature: SignatureBytes,
+}
+
+impl Login {
+    pub fn new(keypair: &DalekKeypair, chain_id: ChainID) -> Self {
+        Self::signed(keypair, chain_id, unix_secs(SystemTime::now()))
+    }
+
+    /// Checks that the login is meant for `chain_id`, recent and signed by its key.
+    pub fn verify(&self, chain_id: ChainID) -> Result<()> {
+        if self.chain_id != chain_id {
+            return Err(anyhow!(
+                "login for chain {}, this is chain {}",
+                self.chain_id,
+                chain_id
+            ));
+        }
+        let now = unix_secs(SystemTime::now());
+        if now.abs_diff(self.timestamp) > LOGIN_VALIDITY.as_secs() {
+            return Err(anyhow!(
+                "login timestamp {} is too far from now",
+                self.timestamp
+            ));
+        }
+        let bytes = Self::signed_bytes(self.chain_id, &self.public_key, self.timestamp);
+        if !crypto::verify(&self.public_key, &bytes, &self.signature) {
+            return Err(anyhow!("invalid signature"));
+        }
+        Ok(())
+    }
+
+    fn signed(keypair: &DalekKeypair, chain_id: ChainID, timestamp: u64) -> Self {
+        let public_key = keypair.public.to_bytes();
+        let signature = crypto::sign(
+            keypair,
+            &Self::signed_bytes(chain_id, &public_key, timestamp),
+        );
+        Self {
+            chain_id,
+            public_key,
+            timestamp,
+            signature,
+        }
+    }
+
+    fn signed_bytes(chain_id: ChainID, public_key: &PublicKeyBytes, timestamp: u64) -> Vec<u8> {
+        (b"login", chain_id, public_key, timestamp)
+            .try_to_vec()
+            .unwrap()
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 #[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
 pub struct NewTransactionRequest {
+    /// Chain the transaction is meant for, nodes of other chains reject it.
+    pub chain_id: ChainID,
     pub requester: PublicKeyBytes,
+    /// Hash of the data, see [`TransactionData::hash`].
     pub hash: TransactionHash,
-    pub data: Vec<u8>,
+    pub data: TransactionData,
+    /// Signature of the requester over the fields above.
+    pub signature: SignatureBytes,
+}
+
+impl NewTransactionRequest {
+    pub fn new(keypair: &DalekKeypair, chain_id: ChainID, data: TransactionData) -> Self {
+        let requester = keypair.public.to_bytes();
+        let hash = data.hash();
+        let signature = crypto::sign(
+            keypair,
+            &Self::signed_bytes(chain_id, &requester, &hash, &data),
+        );
+        Self {
+            chain_id,
+            requester,
+            hash,
+            data,
+            signature,
+        }
+    }
+
+    /// Checks that the request is meant for `chain_id`, signed by its requester and that
+    /// its hash is that of its data, so that proofs of the hash stand for the data.
+    pub fn verify(&self, chain_id: ChainID) -> Result<()> {
+        if self.chain_id != chain_id {
+            return Err(anyhow!(
+                "transaction for chain {}, this is chain {}",
+                self.chain_id,
+                chain_id
+            ));
+        }
+        if self.hash != self.data.hash() {
+            return Err(anyhow!("hash does not match the data"));
+        }
+        let bytes = Self::signed_bytes(self.chain_id, &self.requester, &self.hash, &self.data);
+        if !crypto::verify(&self.requester, &bytes, &self.signature) {
+            return Err(anyhow!("invalid signature"));
+        }
+        Ok(())
+    }
+
+    fn signed_bytes(
+        chain_id: ChainID,
+        requester: &PublicKeyBytes,
+        hash: &TransactionHash,
+        data: &TransactionData,
+    ) -> Vec<u8> {
+        (chain_id, requester, hash, data).try_to_vec().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub enum TransactionData {
+    /// Opaque application payload.
+    App(Vec<u8>),
+    Governance(GovernanceTransaction),
+}
+
+impl TransactionData {
+    /// SHA-256 of the app payload, or of the borsh encoded governance transaction.
+    pub fn hash(&self) -> TransactionHash {
+        let mut hasher = CryptoHasher::new();
+        match self {
+            TransactionData::App(payload) => hasher.update(payload),
+            TransactionData::Governance(transaction) => {
+                hasher.update(transaction.try_to_vec().unwrap())
+            }
+        }
+        hasher.finalize().into()
+    }
 }
 
 #[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
@@ -16,10 +185,131 @@ pub struct TransactionReceipt {
     pub requester: PublicKeyBytes,
     pub hash: TransactionHash,
     pub result: TransactionResult,
+    /// Signature of the receiptor's validator key over the fields above and the chain ID.
+    pub signature: SignatureBytes,
+    /// Proof of the commit when the node has one at hand, it is enough on its own and is
+    /// not covered by the signature.
+    pub proof: Option<Box<CommitProof>>,
+}
+
+impl TransactionReceipt {
+    pub fn new(
+        keypair: &DalekKeypair,
+        chain_id: ChainID,
+        requester: PublicKeyBytes,
+        hash: TransactionHash,
+        result: TransactionResult,
+    ) -> Self {
+        let receiptor = keypair.public.to_bytes();
+        let signature = crypto::sign(
+            keypair,
+            &Self::signed_bytes(chain_id, &receiptor, &requester, &hash, &result),
+        );
+        Self {
+            receiptor,
+            requester,
+            hash,
+            result,
+            signature,
+            proof: None,
+        }
+    }
+
+    /// Checks that the receipt is signed by its receiptor for `chain_id`. Whether the
+    /// receiptor is a validator is up to the caller.
+    pub fn verify(&self, chain_id: ChainID) -> Result<()> {
+        let bytes = Self::signed_bytes(
+            chain_id,
+            &self.receiptor,
+            &self.requester,
+            &self.hash,
+            &self.result,
+        );
+        if !crypto::verify(&self.receiptor, &bytes, &self.signature) {
+            return Err(anyhow!("invalid signature"));
+        }
+        Ok(())
+    }
+
+    fn signed_bytes(
+        chain_id: ChainID,
+        receiptor: &PublicKeyBytes,
+        requester: &PublicKeyBytes,
+        hash: &TransactionHash,
+        result: &TransactionResult,
+    ) -> Vec<u8> {
+        (b"receipt", chain_id, receiptor, requester, hash, result)
+            .try_to_vec()
+            .unwrap()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
 pub enum TransactionResult {
     Commited,
-    Unaccepted,
+    Unaccepted {
+        reason: String,
+    },
+    /// The node did not take the transaction, it is to be submitted again after this
+    /// many milliseconds.
+    Busy {
+        retry_after_ms: u64,
+    },
+}
+
+#[cfg(test)]
+mod message_tests {
+    use super::*;
+    use crate::crypto::generate_keypair;
+
+    #[test]
+    fn request_signature_test() {
+        let keypair = generate_keypair();
+        let request = NewTransactionRequest::new(&keypair, 1, TransactionData::App(vec![1, 2]));
+        request.verify(1).unwrap();
+        // Cross-wired clusters reject each other's transactions.
+        assert!(request.verify(2).is_err());
+        let mut forged = request.clone();
+        forged.chain_id = 2;
+        assert!(forged.verify(2).is_err());
+        let mut forged = request.clone();
+        forged.data = TransactionData::App(vec![3]);
+        assert!(forged.verify(1).is_err());
+        // Signed by the requester, yet the hash does not stand for the data.
+        let mut forged = request;
+        forged.hash = [1; 32];
+        forged.signature = crypto::sign(
+            &keypair,
+            &NewTransactionRequest::signed_bytes(1, &forged.requester, &forged.hash, &forged.data),
+        );
+        assert!(forged.verify(1).is_err());
+    }
+
+    #[test]
+    fn receipt_signature_test() {
+        let keypair = generate_keypair();
+        let receipt =
+            TransactionReceipt::new(&keypair, 1, [2; 32], [3; 32], TransactionResult::Commited);
+        receipt.verify(1).unwrap();
+        assert!(receipt.verify(2).is_err());
+        // A relay cannot turn a rejection into a commit, nor pass it off as another node's.
+        let mut forged = receipt.clone();
+        forged.result = TransactionResult::Busy { retry_after_ms: 0 };
+        assert!(forged.verify(1).is_err());
+        let mut forged = receipt;
+        forged.receiptor = generate_keypair().public.to_bytes();
+        assert!(forged.verify(1).is_err());
+    }
+
+    #[test]
+    fn login_test() {
+        let keypair = generate_keypair();
+        Login::new(&keypair, 1).verify(1).unwrap();
+        assert!(Login::new(&keypair, 1).verify(2).is_err());
+        let stale = unix_secs(SystemTime::now()) - 2 * LOGIN_VALIDITY.as_secs();
+        assert!(Login::signed(&keypair, 1, stale).verify(1).is_err());
+        let mut forged = Login::new(&keypair, 1);
+        forged.public_key = generate_keypair().public.to_bytes();
+        assert!(forged.verify(1).is_err());
+    }
 }
@@ -0,0 +1,347 @@
+use crate::crypto::publickey_to_base64;
+use crate::message::{NewTransactionRequest, TransactionHash};
+
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
+use hotstuff_rs::types::{
+    Block, BlockHeight, ChainID, CryptoHash, CryptoHasher, QuorumCertificate, ValidatorSet,
+};
+use sha2::Digest;
+
+/// What a block hash is computed from, the block data is left out.
+#[derive(Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct BlockHeader {
+    pub height: BlockHeight,
+    pub justify: QuorumCertificate,
+    /// Merkle root of the hashes of the transactions in the block.
+    pub data_hash: CryptoHash,
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> CryptoHash {
+        Block::hash(self.height, &self.justify, &self.data_hash)
+    }
+}
+
+impl fmt::Debug for BlockHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockHeader")
+            .field("height", &self.height)
+            .field("hash", &publickey_to_base64(self.hash()))
+            .field("justify_view", &self.justify.view)
+            .field("justify_phase", &self.justify.phase)
+            .finish()
+    }
+}
+
+/// Merkle root of the transaction hashes of a block, which is its data hash. A lone
+/// transaction is its own root, an empty block has the zero hash.
+pub fn merkle_root(leaves: &[CryptoHash]) -> CryptoHash {
+    if leaves.is_empty() {
+        return CryptoHash::default();
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = parent_level(&level);
+    }
+    level[0]
+}
+
+/// Pairs up the nodes of a level, an odd one out moves up as it is.
+fn parent_level(level: &[CryptoHash]) -> Vec<CryptoHash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => parent(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn parent(left: &CryptoHash, right: &CryptoHash) -> CryptoHash {
+    let mut hasher = CryptoHasher::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The siblings on the way from a transaction hash up to the Merkle root of its block.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct InclusionProof {
+    pub index: u32,
+    /// Number of transactions in the block, it fixes the shape of the tree.
+    pub leaves: u32,
+    pub path: Vec<CryptoHash>,
+}
+
+impl InclusionProof {
+    /// Proof of the leaf at `index`, `None` when out of range.
+    pub fn new(leaves: &[CryptoHash], index: usize) -> Option<Self> {
+        if index >= leaves.len() {
+            return None;
+        }
+        let mut path = vec![];
+        let mut level = leaves.to_vec();
+        let mut position = index;
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(position ^ 1) {
+                path.push(*sibling);
+            }
+            level = parent_level(&level);
+            position /= 2;
+        }
+        Some(Self {
+            index: index as u32,
+            leaves: leaves.len() as u32,
+            path,
+        })
+    }
+
+    /// The root `leaf` leads to, `None` when the path does not fit the tree.
+    pub fn root(&self, leaf: &CryptoHash) -> Option<CryptoHash> {
+        let (mut position, mut width) = (self.index as usize, self.leaves as usize);
+        if position >= width {
+            return None;
+        }
+        let mut path = self.path.iter();
+        let mut hash = *leaf;
+        while width > 1 {
+            if position ^ 1 < width {
+                let sibling = path.next()?;
+                hash = if position % 2 == 0 {
+                    parent(&hash, sibling)
+                } else {
+                    parent(sibling, &hash)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        path.next().is_none().then_some(hash)
+    }
+}
+
+/// Shows on its own that a transaction is committed: the header of its block, where the
+/// transaction sits in it, and quorum certificates of descendants the commit rule of
+/// HotStuff is satisfied with.
+#[derive(Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct CommitProof {
+    pub header: BlockHeader,
+    pub inclusion: InclusionProof,
+    /// Descendants of the block, each one justifying the one before.
+    pub descendants: Vec<BlockHeader>,
+    /// Certificate of the last descendant, of the block itself when there is none.
+    pub qc: QuorumCertificate,
+}
+
+impl CommitProof {
+    pub fn height(&self) -> BlockHeight {
+        self.header.height
+    }
+
+    pub fn block_hash(&self) -> CryptoHash {
+        self.header.hash()
+    }
+
+    /// Checks that `transaction` is in the block and that the block is committed, with
+    /// certificates signed by a quorum of `validator_set`. That is the validator set in
+    /// charge when the block committed, a proof across a validator set change fails.
+    pub fn verify(
+        &self,
+        chain_id: ChainID,
+        validator_set: &ValidatorSet,
+        transaction: &TransactionHash,
+    ) -> Result<()> {
+        if self.inclusion.root(transaction) != Some(self.header.data_hash) {
+            return Err(anyhow!("transaction not in the block"));
+        }
+        verify_commit(
+            chain_id,
+            self.header.hash(),
+            &self.descendants,
+            &self.qc,
+            (validator_set, validator_set),
+        )
+    }
+}
+
+/// Checks that `descendants` and `qc` follow `block` and commit it. The certificate of
+/// `block` itself is checked against the first of `validator_sets`, later ones against the
+/// second, which differ when the block changes the validator set.
+pub(crate) fn verify_commit(
+    chain_id: ChainID,
+    block: CryptoHash,
+    descendants: &[BlockHeader],
+    qc: &QuorumCertificate,
+    (signers, validator_set): (&ValidatorSet, &ValidatorSet),
+) -> Result<()> {
+    let mut hash = block;
+    let mut certifiers = signers;
+    for descendant in descendants {
+        if descendant.justify.block != hash {
+            return Err(anyhow!("block {} does not follow", descendant.height));
+        }
+        verify_qc(&descendant.justify, chain_id, certifiers)?;
+        hash = descendant.hash();
+        certifiers = validator_set;
+    }
+    if qc.block != hash {
+        return Err(anyhow!("certificate of another block"));
+    }
+    verify_qc(qc, chain_id, certifiers)?;
+    // A commit certificate commits its block right away, three generic ones in a row
+    // commit the first block of the chain.
+    let committed = qc.phase.is_commit() || (qc.phase.is_generic() && descendants.len() >= 2);
+    if !committed {
+        return Err(anyhow!("certificates fall short of a commit"));
+    }
+    Ok(())
+}
+
+pub(crate) fn verify_qc(
+    qc: &QuorumCertificate,
+    chain_id: ChainID,
+    validator_set: &ValidatorSet,
+) -> Result<()> {
+    if qc.chain_id != chain_id {
+        return Err(anyhow!(
+            "certificate for chain {}, this is chain {}",
+            qc.chain_id,
+            chain_id
+        ));
+    }
+    // The genesis certificate is correct without any signature.
+    if qc.is_genesis_qc() || !qc.is_correct(validator_set) {
+        return Err(anyhow!("invalid certificate of view {}", qc.view));
+    }
+    Ok(())
+}
+
+impl fmt::Debug for CommitProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitProof")
+            .field("header", &self.header)
+            .field("inclusion", &self.inclusion)
+            .field("descendants", &self.descendants)
+            .field("qc_view", &self.qc.view)
+            .field("qc_phase", &self.qc.phase)
+            .finish()
+    }
+}
+
+/// Asks a node for the committed headers from `start_height` on, at most `count` of them.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct HeaderRequest {
+    pub start_height: BlockHeight,
+    pub count: u32,
+}
+
+/// A header of the committed chain with every transaction of its block. They are checked
+/// against the data hash, so a node cannot leave out the governance transactions.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct CommittedHeader {
+    pub header: BlockHeader,
+    pub transactions: Vec<NewTransactionRequest>,
+}
+
+/// Consecutive committed headers, and what proves the last of them committed as in a
+/// [`CommitProof`].
+#[derive(Clone, BorshSerialize, BorshDeserialize)]
+pub struct HeaderChain {
+    pub headers: Vec<CommittedHeader>,
+    pub descendants: Vec<BlockHeader>,
+    pub qc: QuorumCertificate,
+}
+
+impl fmt::Debug for HeaderChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeaderChain")
+            .field("headers", &self.headers)
+            .field("descendants", &self.descendants)
+            .field("qc_view", &self.qc.view)
+            .field("qc_phase", &self.qc.phase)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod proof_tests {
+    use super::*;
+    use crate::crypto::{self, generate_keypair};
+    use hotstuff_rs::types::{DalekKeypair, Phase};
+
+    #[test]
+    fn inclusion_test() {
+        for count in 1..=7u8 {
+            let leaves: Vec<CryptoHash> = (0..count).map(|n| [n; 32]).collect();
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = InclusionProof::new(&leaves, index).unwrap();
+                assert_eq!(proof.root(leaf), Some(root));
+                assert_ne!(proof.root(&[9; 32]), Some(root));
+            }
+        }
+        assert_eq!(merkle_root(&[[1; 32]]), [1; 32]);
+        assert!(InclusionProof::new(&[[1; 32]], 1).is_none());
+    }
+
+    #[test]
+    fn commit_proof_test() {
+        let keypair = generate_keypair();
+        let mut validator_set = ValidatorSet::new();
+        validator_set.put(&keypair.public.to_bytes(), 1);
+        let qc = |keypair: &DalekKeypair, header: &BlockHeader, phase| {
+            let (view, block) = (header.height, header.hash());
+            let bytes = (1u64, view, block, phase).try_to_vec().unwrap();
+            QuorumCertificate {
+                chain_id: 1,
+                view,
+                block,
+                phase,
+                signatures: vec![Some(crypto::sign(keypair, &bytes))],
+            }
+        };
+        let leaves = [[1; 32], [2; 32], [3; 32]];
+        let header = BlockHeader {
+            height: 5,
+            justify: QuorumCertificate::genesis_qc(),
+            data_hash: merkle_root(&leaves),
+        };
+        let first = BlockHeader {
+            height: 6,
+            justify: qc(&keypair, &header, Phase::Generic),
+            data_hash: CryptoHash::default(),
+        };
+        let second = BlockHeader {
+            height: 7,
+            justify: qc(&keypair, &first, Phase::Generic),
+            data_hash: CryptoHash::default(),
+        };
+        let proof = CommitProof {
+            header: header.clone(),
+            inclusion: InclusionProof::new(&leaves, 1).unwrap(),
+            descendants: vec![first.clone(), second.clone()],
+            qc: qc(&keypair, &second, Phase::Generic),
+        };
+        proof.verify(1, &validator_set, &[2; 32]).unwrap();
+        assert!(proof.verify(1, &validator_set, &[3; 32]).is_err());
+        assert!(proof.verify(2, &validator_set, &[2; 32]).is_err());
+        let mut strangers = ValidatorSet::new();
+        strangers.put(&generate_keypair().public.to_bytes(), 1);
+        assert!(proof.verify(1, &strangers, &[2; 32]).is_err());
+
+        // Two generic certificates are not enough, a commit certificate is.
+        let mut short = proof.clone();
+        short.descendants.pop();
+        short.qc = qc(&keypair, &first, Phase::Generic);
+        assert!(short.verify(1, &validator_set, &[2; 32]).is_err());
+        short.qc = qc(&keypair, &first, Phase::Commit(6));
+        short.verify(1, &validator_set, &[2; 32]).unwrap();
+        let mut broken = proof;
+        broken.descendants.remove(0);
+        assert!(broken.verify(1, &validator_set, &[2; 32]).is_err());
+    }
+}
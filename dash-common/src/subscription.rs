@@ -0,0 +1,75 @@
+use crate::governance::ValidatorChange;
+use crate::message::{NewTransactionRequest, TransactionData};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use hotstuff_rs::types::{BlockHeight, CryptoHash, PublicKeyBytes, ViewNumber};
+
+/// A request for a feed of the committed chain.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct Subscription {
+    pub topic: Topic,
+    /// Committed blocks from this height on are replayed first, `None` starts with the next
+    /// block to commit.
+    pub start_height: Option<BlockHeight>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Topic {
+    /// Every committed block, empty ones included.
+    Blocks,
+    /// Committed transactions of a requester.
+    Requester(PublicKeyBytes),
+    /// Changes of the app state carried by committed transactions.
+    Events,
+}
+
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct CommittedBlock {
+    pub height: BlockHeight,
+    pub hash: CryptoHash,
+    /// View the block was proposed in, `None` when the node no longer holds its quorum
+    /// certificate.
+    pub view: Option<ViewNumber>,
+    /// Leader of that view, `None` as well when the validator set changed around the block
+    /// and the leader may have been chosen from another one.
+    pub proposer: Option<PublicKeyBytes>,
+    pub transactions: Vec<NewTransactionRequest>,
+}
+
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct CommittedTransaction {
+    pub height: BlockHeight,
+    pub block: CryptoHash,
+    pub request: NewTransactionRequest,
+}
+
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct CommittedEvent {
+    pub height: BlockHeight,
+    pub block: CryptoHash,
+    pub event: AppEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum AppEvent {
+    /// A governance transaction changed the validator set. It takes effect once the block
+    /// after it commits.
+    ValidatorSetChanged {
+        nonce: u64,
+        changes: Vec<ValidatorChange>,
+    },
+}
+
+impl AppEvent {
+    /// The event a committed transaction gives rise to, app payloads are opaque and give
+    /// none.
+    pub fn of(data: &TransactionData) -> Option<Self> {
+        match data {
+            TransactionData::App(_) => None,
+            TransactionData::Governance(transaction) => Some(AppEvent::ValidatorSetChanged {
+                nonce: transaction.nonce,
+                changes: transaction.changes.clone(),
+            }),
+        }
+    }
+}
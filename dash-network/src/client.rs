@@ -1,7 +1,10 @@
-use crate::common::Channel;
+use crate::common::{
+    is_oversized, Channel, Frame, FrameCodec, Handle, Liveness, Options, RateLimiter, Writer,
+};
+use crate::stats::{PeerHandle, Stats};
 
 use std::collections::{HashMap, VecDeque};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::result::Result;
 use std::time::Duration;
@@ -11,63 +14,113 @@ use futures::{SinkExt, StreamExt};
 use log::{trace, warn};
 use tokio::{
     net::TcpStream,
-    sync::mpsc::{channel, Receiver, Sender},
-    time,
+    sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedReceiver},
+    time::{self, Instant},
 };
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::{codec::Framed, sync::CancellationToken, task::TaskTracker};
 
 pub struct Client {
+    options: Options,
+    stats: Stats,
+    cancel: CancellationToken,
+    tasks: TaskTracker,
     sender: Sender<(SocketAddr, Bytes)>,
     receiver: Receiver<(SocketAddr, Bytes)>,
-    sender_workers: HashMap<SocketAddr, Sender<Bytes>>,
+    disconnects: UnboundedReceiver<SocketAddr>,
+    sender_workers: HashMap<SocketAddr, (Sender<Bytes>, PeerHandle, CancellationToken)>,
 }
 
 impl Client {
-    pub fn spawn() -> Channel {
+    pub fn spawn(options: Options) -> (Channel, Handle) {
         let (sender, ret_receiver) = channel(1000);
         let (ret_sender, receiver) = channel(1000);
-        tokio::spawn(async move {
-            Self {
-                sender,
-                receiver,
-                sender_workers: Default::default(),
-            }
-            .run()
-            .await;
-        });
-        (ret_sender, ret_receiver)
+        let (disconnect_sender, disconnects) = unbounded_channel();
+        let mut handle = Handle::new(None);
+        handle.disconnects = Some(disconnect_sender);
+        let mut client = Self {
+            options,
+            stats: handle.stats.clone(),
+            cancel: handle.cancel.clone(),
+            tasks: handle.tasks.clone(),
+            sender,
+            receiver,
+            disconnects,
+            sender_workers: Default::default(),
+        };
+        handle.tasks.spawn(async move { client.run().await });
+        ((ret_sender, ret_receiver), handle)
     }
 
     async fn run(&mut self) {
-        while let Some((dest_addr, data)) = self.receiver.recv().await {
-            let sender = self
-                .sender_workers
-                .entry(dest_addr)
-                .or_insert_with(|| Connection::spawn(dest_addr, self.sender.clone()));
-            sender.send(data).await.unwrap();
+        loop {
+            let (dest_addr, data) = tokio::select! {
+                Some(msg) = self.receiver.recv() => msg,
+                Some(addr) = self.disconnects.recv() => {
+                    if let Some((_, _, cancel)) = self.sender_workers.remove(&addr) {
+                        trace!("disconnect from {}", addr);
+                        cancel.cancel();
+                        self.stats.remove_peer(&addr);
+                    }
+                    continue;
+                }
+                () = self.cancel.cancelled() => break,
+                else => break,
+            };
+            let (sender, peer, _) = self.sender_workers.entry(dest_addr).or_insert_with(|| {
+                let peer = self.stats.peer_counters(dest_addr);
+                let cancel = self.cancel.child_token();
+                let sender = Connection::spawn(
+                    dest_addr,
+                    self.options,
+                    peer.clone(),
+                    self.sender.clone(),
+                    &self.tasks,
+                    cancel.clone(),
+                );
+                (sender, peer, cancel)
+            });
+            peer.record_queued();
+            if sender.send(data).await.is_err() {
+                // Only happens once the connection is cancelled.
+                peer.record_dropped(1);
+            }
         }
     }
 }
 
 struct Connection {
     remote_addr: SocketAddr,
+    options: Options,
+    peer: PeerHandle,
     sender: Sender<(SocketAddr, Bytes)>,
     receiver: Receiver<Bytes>,
     buffer: VecDeque<Bytes>,
 }
 
 impl Connection {
-    fn spawn(remote_addr: SocketAddr, sender: Sender<(SocketAddr, Bytes)>) -> Sender<Bytes> {
+    fn spawn(
+        remote_addr: SocketAddr,
+        options: Options,
+        peer: PeerHandle,
+        sender: Sender<(SocketAddr, Bytes)>,
+        tasks: &TaskTracker,
+        cancel: CancellationToken,
+    ) -> Sender<Bytes> {
         let (ret_sender, receiver) = channel(1000);
-        tokio::spawn(async move {
-            Self {
+        tasks.spawn(async move {
+            let mut connection = Self {
                 remote_addr,
+                options,
+                peer,
                 sender,
                 receiver,
                 buffer: Default::default(),
+            };
+            tokio::select! {
+                () = connection.run() => (),
+                () = cancel.cancelled() => trace!("close connection to {}", remote_addr),
             }
-            .run()
-            .await
+            connection.peer.record_connected(false);
         });
         ret_sender
     }
@@ -86,9 +139,11 @@ impl Connection {
 
                     // Try to transmit all messages in the buffer and keep transmitting incoming messages.
                     // The following function only returns if there is an error.
+                    self.peer.record_connected(true);
                     if let Err(e) = self.keep_alive(stream).await {
-                        warn!("{}", e);
+                        warn!("{}: {}", self.remote_addr, e);
                     }
+                    self.peer.record_connected(false);
                 }
                 Err(e) => {
                     warn!(
@@ -114,6 +169,7 @@ impl Connection {
                                 if self.buffer.len() > 1000 {
                                     warn!("400 msg droped");
                                     self.buffer.drain(0..400);
+                                    self.peer.record_dropped(400);
                                 }
                             }
                         }
@@ -124,22 +180,82 @@ impl Connection {
     }
 
     async fn keep_alive(&mut self, stream: TcpStream) -> Result<(), Error> {
-        let (mut writer, mut reader) = Framed::new(stream, LengthDelimitedCodec::new()).split();
+        let codec = FrameCodec::new(&self.options.frame_limits);
+        let (mut writer, mut reader) = Framed::new(stream, codec).split();
+        let mut limiter = RateLimiter::new(self.options.frame_limits);
+        let mut liveness = Liveness::new(self.options.heartbeat);
+        if let Some(id) = self.options.network_id {
+            writer.send(Frame::Hello(id)).await?;
+        }
         while let Some(data) = self.buffer.pop_front() {
             trace!("send msg to {} in keep_alive", self.remote_addr);
-            writer.send(data).await?;
+            self.write(&mut writer, data).await?;
         }
+        let mut heartbeat = time::interval(self.options.heartbeat.interval);
+        // Reading is paused until the timer fires once the decode budget is exhausted.
+        let resume = time::sleep_until(Instant::now());
+        tokio::pin!(resume);
+        let mut throttled = false;
         loop {
             tokio::select! {
                 Some(data) = self.receiver.recv() => {
                     trace!("send msg to {} in keep_alive", self.remote_addr);
-                    writer.send(data).await?;
+                    self.write(&mut writer, data).await?;
+                }
+                _ = heartbeat.tick() => {
+                    let ping = liveness.tick()?;
+                    self.peer.record_missed_heartbeats(liveness.missed());
+                    writer.send(ping).await?;
                 }
-                Some(data) = reader.next() => {
-                    let data = data?.freeze();
-                    self.sender.send((self.remote_addr, data)).await.unwrap();
+                () = &mut resume, if throttled => throttled = false,
+                frame = reader.next(), if !throttled => {
+                    let frame = match frame {
+                        Some(Ok(frame)) => frame,
+                        Some(Err(e)) => {
+                            if is_oversized(&e) {
+                                self.peer.stats().record_oversized_frame();
+                            }
+                            return Err(e);
+                        }
+                        None => return Err(ErrorKind::UnexpectedEof.into()),
+                    };
+                    self.peer.record_seen();
+                    match frame {
+                        Frame::Data(data) => {
+                            self.peer.record_received(data.len());
+                            if let Some(until) = limiter.check(data.len()) {
+                                trace!("throttle reading from {}", self.remote_addr);
+                                self.peer.stats().record_throttled();
+                                resume.as_mut().reset(until);
+                                throttled = true;
+                            }
+                            self.sender.send((self.remote_addr, data)).await.unwrap();
+                        }
+                        Frame::Ping(nonce) => writer.send(Frame::Pong(nonce)).await?,
+                        Frame::Pong(nonce) => {
+                            if let Some(rtt) = liveness.pong(nonce) {
+                                self.peer.record_rtt(rtt);
+                            }
+                        }
+                        Frame::Hello(_) => (),
+                    }
                 }
             }
         }
     }
+
+    /// Writes a data frame, a message that fails to be written is lost.
+    async fn write(&self, writer: &mut Writer, data: Bytes) -> Result<(), Error> {
+        let len = data.len();
+        match writer.send(Frame::Data(data)).await {
+            Ok(()) => {
+                self.peer.record_sent(len);
+                Ok(())
+            }
+            Err(e) => {
+                self.peer.record_dropped(1);
+                Err(e)
+            }
+        }
+    }
 }
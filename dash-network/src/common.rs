@@ -1,14 +1,393 @@
+use crate::stats::Stats;
+
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use bytes::Bytes;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::stream::{SplitSink, SplitStream};
 use tokio::{
     net::TcpStream,
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{Receiver, Sender, UnboundedSender},
+    time::Instant,
+};
+use tokio_util::{
+    codec::{Decoder, Encoder, Framed, LengthDelimitedCodec, LengthDelimitedCodecError},
+    sync::CancellationToken,
+    task::TaskTracker,
 };
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 /// Convenient alias for the writer end of the TCP channel.
-pub type Writer = SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>;
-pub type Reader = SplitStream<Framed<TcpStream, LengthDelimitedCodec>>;
+pub(crate) type Writer = SplitSink<Framed<TcpStream, FrameCodec>, Frame>;
+pub(crate) type Reader = SplitStream<Framed<TcpStream, FrameCodec>>;
 pub type Channel = (Sender<(SocketAddr, Bytes)>, Receiver<(SocketAddr, Bytes)>);
+
+/// Options shared by every connection of a [`Client`](crate::client::Client) or
+/// [`Server`](crate::server::Server).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub frame_limits: FrameLimits,
+    pub heartbeat: Heartbeat,
+    /// Servers only accept connections from clients presenting the same id, e.g. nodes
+    /// sharing a genesis. `None` accepts everyone and presents nothing.
+    pub network_id: Option<NetworkId>,
+}
+
+pub type NetworkId = [u8; 32];
+
+/// Keepalive policy. A ping is sent every `interval`, and the connection is closed once
+/// `max_missed` pings in a row went unanswered.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            max_missed: 3,
+        }
+    }
+}
+
+/// Limits applied to every frame of a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    /// Frames read longer than this are rejected and the connection is closed.
+    pub max_frame_length: usize,
+    /// Frames longer than this are not written and the connection is closed. Answers may
+    /// well be larger than requests, so it is set apart from `max_frame_length`.
+    pub max_send_frame_length: usize,
+    /// Frames decoded per connection per second before reading is paused.
+    pub max_frames_per_sec: u32,
+    /// Bytes decoded per connection per second before reading is paused.
+    pub max_bytes_per_sec: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_length: 8 * 1024 * 1024,
+            max_send_frame_length: 8 * 1024 * 1024,
+            max_frames_per_sec: 10_000,
+            max_bytes_per_sec: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Frames on the wire, the payload of each length delimited frame is prefixed with a tag byte.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Frame {
+    Data(Bytes),
+    Ping(u64),
+    Pong(u64),
+    /// First frame of a client connection when a network id is set.
+    Hello(NetworkId),
+}
+
+const TAG_DATA: u8 = 0;
+const TAG_PING: u8 = 1;
+const TAG_PONG: u8 = 2;
+const TAG_HELLO: u8 = 3;
+
+pub(crate) struct FrameCodec {
+    codec: LengthDelimitedCodec,
+    max_send_frame_length: usize,
+}
+
+impl FrameCodec {
+    pub(crate) fn new(limits: &FrameLimits) -> Self {
+        Self {
+            codec: LengthDelimitedCodec::builder()
+                .max_frame_length(limits.max_frame_length)
+                .new_codec(),
+            max_send_frame_length: limits.max_send_frame_length,
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        let Some(mut frame) = self.codec.decode(src)? else {
+            return Ok(None);
+        };
+        if frame.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "empty frame"));
+        }
+        let tag = frame.get_u8();
+        match tag {
+            TAG_DATA => Ok(Some(Frame::Data(frame.freeze()))),
+            TAG_PING | TAG_PONG if frame.len() == 8 => {
+                let nonce = frame.get_u64();
+                if tag == TAG_PING {
+                    Ok(Some(Frame::Ping(nonce)))
+                } else {
+                    Ok(Some(Frame::Pong(nonce)))
+                }
+            }
+            TAG_HELLO if frame.len() == 32 => {
+                let mut id = NetworkId::default();
+                frame.copy_to_slice(&mut id);
+                Ok(Some(Frame::Hello(id)))
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "malformed frame")),
+        }
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        let (tag, payload) = match frame {
+            Frame::Data(data) => (TAG_DATA, data),
+            Frame::Ping(nonce) => (TAG_PING, Bytes::copy_from_slice(&nonce.to_be_bytes())),
+            Frame::Pong(nonce) => (TAG_PONG, Bytes::copy_from_slice(&nonce.to_be_bytes())),
+            Frame::Hello(id) => (TAG_HELLO, Bytes::copy_from_slice(&id)),
+        };
+        let len = payload.len() + 1;
+        if len > self.max_send_frame_length {
+            return Err(Error::new(ErrorKind::InvalidInput, "frame size too big"));
+        }
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        dst.put_u8(tag);
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+/// Whether a read error was caused by a frame exceeding `max_frame_length`.
+pub(crate) fn is_oversized(e: &Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<LengthDelimitedCodecError>())
+}
+
+/// Tracks the outstanding ping of a connection.
+pub(crate) struct Liveness {
+    heartbeat: Heartbeat,
+    nonce: u64,
+    sent_at: Option<Instant>,
+    missed: u32,
+}
+
+impl Liveness {
+    pub(crate) fn new(heartbeat: Heartbeat) -> Self {
+        Self {
+            heartbeat,
+            nonce: 0,
+            sent_at: None,
+            missed: 0,
+        }
+    }
+
+    /// Called on every heartbeat tick, returns the ping to send, or an error once too many
+    /// pings went unanswered.
+    pub(crate) fn tick(&mut self) -> Result<Frame, Error> {
+        if self.sent_at.is_some() {
+            self.missed += 1;
+            if self.missed >= self.heartbeat.max_missed {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("{} heartbeats missed", self.missed),
+                ));
+            }
+        }
+        self.nonce = self.nonce.wrapping_add(1);
+        self.sent_at = Some(Instant::now());
+        Ok(Frame::Ping(self.nonce))
+    }
+
+    /// Returns the round trip time if `nonce` answers the latest ping.
+    pub(crate) fn pong(&mut self, nonce: u64) -> Option<Duration> {
+        // A stale or forged pong must not keep a dead peer alive.
+        if nonce != self.nonce {
+            return None;
+        }
+        let sent_at = self.sent_at.take()?;
+        self.missed = 0;
+        Some(sent_at.elapsed())
+    }
+
+    pub(crate) fn missed(&self) -> u32 {
+        self.missed
+    }
+}
+
+/// Handle to a spawned [`Client`](crate::client::Client) or [`Server`](crate::server::Server).
+#[derive(Clone)]
+pub struct Handle {
+    pub(crate) local_addr: Option<SocketAddr>,
+    pub(crate) stats: Stats,
+    pub(crate) cancel: CancellationToken,
+    pub(crate) tasks: TaskTracker,
+    pub(crate) disconnects: Option<UnboundedSender<SocketAddr>>,
+}
+
+impl Handle {
+    pub(crate) fn new(local_addr: Option<SocketAddr>) -> Self {
+        Self {
+            local_addr,
+            stats: Stats::default(),
+            cancel: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+            disconnects: None,
+        }
+    }
+
+    /// Stops accepting and closes every connection, returns once all of them are gone and,
+    /// for servers, the listening socket is released.
+    pub async fn shutdown(&self) {
+        self.cancel.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// The address a server is bound to, `None` for clients.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Closes the connection of a client to `addr` and drops the messages queued for it,
+    /// sending to `addr` again opens a new one. Does nothing on servers.
+    pub fn disconnect(&self, addr: SocketAddr) {
+        if let Some(disconnects) = &self.disconnects {
+            let _ = disconnects.send(addr);
+        }
+    }
+}
+
+/// Per-connection budget of decode work, reset every second.
+pub(crate) struct RateLimiter {
+    limits: FrameLimits,
+    window_start: Instant,
+    frames: u32,
+    bytes: usize,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: FrameLimits) -> Self {
+        Self {
+            limits,
+            window_start: Instant::now(),
+            frames: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Accounts a decoded frame of `len` bytes. Returns the instant reading may resume at
+    /// if the budget of the current window is exhausted.
+    pub(crate) fn check(&mut self, len: usize) -> Option<Instant> {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.frames = 0;
+            self.bytes = 0;
+        }
+        self.frames += 1;
+        self.bytes += len;
+        if self.frames >= self.limits.max_frames_per_sec
+            || self.bytes >= self.limits.max_bytes_per_sec
+        {
+            Some(self.window_start + Duration::from_secs(1))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod common_tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_test() {
+        let mut limiter = RateLimiter::new(FrameLimits {
+            max_frame_length: 1024,
+            max_send_frame_length: 1024,
+            max_frames_per_sec: 3,
+            max_bytes_per_sec: 100,
+        });
+        assert!(limiter.check(10).is_none());
+        assert!(limiter.check(10).is_none());
+        assert!(limiter.check(10).is_some());
+
+        let mut limiter = RateLimiter::new(FrameLimits {
+            max_frame_length: 1024,
+            max_send_frame_length: 1024,
+            max_frames_per_sec: 100,
+            max_bytes_per_sec: 100,
+        });
+        assert!(limiter.check(60).is_none());
+        assert!(limiter.check(60).is_some());
+    }
+
+    #[test]
+    fn frame_codec_test() {
+        let mut codec = FrameCodec::new(&FrameLimits::default());
+        let mut buf = BytesMut::new();
+        let frames = [
+            Frame::Data(Bytes::from_static(b"data")),
+            Frame::Ping(1),
+            Frame::Pong(u64::MAX),
+        ];
+        for frame in frames.iter() {
+            codec.encode(frame.clone(), &mut buf).unwrap();
+        }
+        for frame in frames {
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(frame));
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.put_u32(2);
+        buf.put_slice(&[TAG_PING, 0]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn liveness_test() {
+        let mut liveness = Liveness::new(Heartbeat {
+            interval: Duration::from_millis(10),
+            max_missed: 2,
+        });
+        let Ok(Frame::Ping(nonce)) = liveness.tick() else {
+            panic!("expect a ping");
+        };
+        assert!(liveness.pong(nonce).is_some());
+        assert!(liveness.tick().is_ok());
+        assert!(liveness.tick().is_ok());
+        assert_eq!(liveness.missed(), 1);
+        assert!(liveness.tick().is_err());
+    }
+
+    #[test]
+    fn mismatched_pong_test() {
+        let mut liveness = Liveness::new(Heartbeat {
+            interval: Duration::from_millis(10),
+            max_missed: 3,
+        });
+        let Ok(Frame::Ping(nonce)) = liveness.tick() else {
+            panic!("expect a ping");
+        };
+        assert!(liveness.tick().is_ok());
+        assert_eq!(liveness.missed(), 1);
+        // The answer to the first ping comes too late.
+        assert!(liveness.pong(nonce).is_none());
+        assert_eq!(liveness.missed(), 1);
+        assert!(liveness.tick().is_ok());
+        assert!(liveness.tick().is_err());
+    }
+}
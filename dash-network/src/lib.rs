@@ -1,3 +1,8 @@
 pub mod client;
 mod common;
+pub mod quic;
 pub mod server;
+pub mod stats;
+pub mod typed;
+
+pub use common::{Channel, FrameLimits, Handle, Heartbeat, NetworkId, Options};
@@ -0,0 +1,614 @@
+//! QUIC transport, an alternative to the TCP connections of [`client`](crate::client) and
+//! [`server`](crate::server) exposing the same [`Channel`]. Every message travels on its own
+//! unidirectional stream, so a large message does not hold back the ones sent after it.
+//!
+//! Endpoints present a self-signed certificate that is not verified, peers are authenticated
+//! by the messages they sign, not by the transport.
+
+use crate::common::{Channel, Handle, Options, RateLimiter};
+use crate::stats::{PeerHandle, Stats};
+
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::io::{self, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use log::{trace, warn};
+use quinn::{
+    ClientConfig, Connecting, ConnectionError, Endpoint, IdleTimeout, ReadToEndError, RecvStream,
+    ServerConfig, TransportConfig, VarInt,
+};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, PrivateKey, ServerName,
+};
+use tokio::{
+    sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedReceiver},
+    time::{self, Instant},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+const SERVER_NAME: &str = "dash";
+
+pub struct Client {
+    /// Endpoints dialing IPv4 and IPv6 peers.
+    endpoints: (Endpoint, Option<Endpoint>),
+    options: Options,
+    stats: Stats,
+    cancel: CancellationToken,
+    tasks: TaskTracker,
+    sender: Sender<(SocketAddr, Bytes)>,
+    receiver: Receiver<(SocketAddr, Bytes)>,
+    disconnects: UnboundedReceiver<SocketAddr>,
+    sender_workers: HashMap<SocketAddr, (Sender<Bytes>, PeerHandle, CancellationToken)>,
+}
+
+impl Client {
+    /// Binds an ephemeral UDP port for IPv4 peers and spawns the dispatching task, must be
+    /// called within a tokio runtime. The port for IPv6 peers is bound once one is dialed.
+    pub fn spawn(options: Options) -> io::Result<(Channel, Handle)> {
+        let endpoint = client_endpoint("0.0.0.0:0".parse().unwrap(), &options)?;
+        let (sender, ret_receiver) = channel(1000);
+        let (ret_sender, receiver) = channel(1000);
+        let (disconnect_sender, disconnects) = unbounded_channel();
+        let mut handle = Handle::new(None);
+        handle.disconnects = Some(disconnect_sender);
+        let mut client = Self {
+            endpoints: (endpoint, None),
+            options,
+            stats: handle.stats.clone(),
+            cancel: handle.cancel.clone(),
+            tasks: handle.tasks.clone(),
+            sender,
+            receiver,
+            disconnects,
+            sender_workers: Default::default(),
+        };
+        handle.tasks.spawn(async move { client.run().await });
+        Ok(((ret_sender, ret_receiver), handle))
+    }
+
+    async fn run(&mut self) {
+        loop {
+            let (dest_addr, data) = tokio::select! {
+                Some(msg) = self.receiver.recv() => msg,
+                Some(addr) = self.disconnects.recv() => {
+                    if let Some((_, _, cancel)) = self.sender_workers.remove(&addr) {
+                        trace!("disconnect from {}", addr);
+                        cancel.cancel();
+                        self.stats.remove_peer(&addr);
+                    }
+                    continue;
+                }
+                () = self.cancel.cancelled() => break,
+                else => break,
+            };
+            if !self.sender_workers.contains_key(&dest_addr) {
+                match self.endpoint(dest_addr) {
+                    Ok(endpoint) => self.connect(dest_addr, endpoint),
+                    Err(e) => {
+                        warn!("Cannot dial {}: {}", dest_addr, e);
+                        self.stats.record_dropped(1);
+                        continue;
+                    }
+                }
+            }
+            let (sender, peer, _) = self.sender_workers.get_mut(&dest_addr).unwrap();
+            peer.record_queued();
+            if sender.send(data).await.is_err() {
+                // Only happens once the connection is cancelled.
+                peer.record_dropped(1);
+            }
+        }
+        self.endpoints.0.close(VarInt::from_u32(0), b"shutdown");
+        if let Some(endpoint) = &self.endpoints.1 {
+            endpoint.close(VarInt::from_u32(0), b"shutdown");
+        }
+    }
+
+    /// The endpoint of the address family of `remote_addr`.
+    fn endpoint(&mut self, remote_addr: SocketAddr) -> io::Result<Endpoint> {
+        if remote_addr.is_ipv4() {
+            return Ok(self.endpoints.0.clone());
+        }
+        if self.endpoints.1.is_none() {
+            let endpoint = client_endpoint("[::]:0".parse().unwrap(), &self.options)?;
+            self.endpoints.1 = Some(endpoint);
+        }
+        Ok(self.endpoints.1.clone().unwrap())
+    }
+
+    fn connect(&mut self, dest_addr: SocketAddr, endpoint: Endpoint) {
+        let (sender, receiver) = channel(1000);
+        let peer = self.stats.peer_counters(dest_addr);
+        let cancel = self.cancel.child_token();
+        let mut connector = Connector {
+            endpoint,
+            remote_addr: dest_addr,
+            link: Link {
+                remote_addr: dest_addr,
+                options: self.options,
+                peer: peer.clone(),
+                sender: self.sender.clone(),
+                tasks: self.tasks.clone(),
+                cancel: cancel.clone(),
+            },
+            receiver,
+            buffer: Default::default(),
+        };
+        self.tasks.spawn(async move { connector.run().await });
+        self.sender_workers
+            .insert(dest_addr, (sender, peer, cancel));
+    }
+}
+
+/// Keeps a connection to a server up, reconnecting like the TCP client does.
+struct Connector {
+    endpoint: Endpoint,
+    remote_addr: SocketAddr,
+    link: Link,
+    receiver: Receiver<Bytes>,
+    buffer: VecDeque<Bytes>,
+}
+
+impl Connector {
+    async fn run(&mut self) {
+        let mut delay = 200;
+        let mut retry = 0;
+        loop {
+            let connecting = self
+                .endpoint
+                .connect(self.remote_addr, SERVER_NAME)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e));
+            let connection = tokio::select! {
+                connection = async { Ok::<_, Error>(connecting?.await?) } => connection,
+                () = self.link.cancel.cancelled() => return,
+            };
+            match connection {
+                Ok(connection) => {
+                    trace!("Outgoing connection established with {}", self.remote_addr);
+                    delay = 200;
+                    retry = 0;
+                    self.link.peer.record_connected(true);
+                    for data in self.buffer.drain(..) {
+                        self.link.write(&connection, data);
+                    }
+                    if let Err(e) = self.link.serve(&connection, &mut self.receiver).await {
+                        warn!("{}: {}", self.remote_addr, e);
+                    }
+                    self.link.peer.record_connected(false);
+                    if self.link.cancel.is_cancelled() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "connect to {}, retry {} times, reason {}",
+                        self.remote_addr, retry, e
+                    );
+                    let timer = time::sleep(Duration::from_millis(delay));
+                    tokio::pin!(timer);
+                    loop {
+                        tokio::select! {
+                            () = &mut timer => {
+                                delay = std::cmp::min(2 * delay, 60_000);
+                                retry += 1;
+                                break;
+                            }
+                            Some(request) = self.receiver.recv() => {
+                                self.buffer.push_back(request);
+                                if self.buffer.len() > 1000 {
+                                    warn!("400 msg droped");
+                                    self.buffer.drain(0..400);
+                                    self.link.peer.record_dropped(400);
+                                }
+                            }
+                            () = self.link.cancel.cancelled() => return,
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Server {
+    endpoint: Endpoint,
+    options: Options,
+    stats: Stats,
+    cancel: CancellationToken,
+    tasks: TaskTracker,
+    sender: Sender<(SocketAddr, Bytes)>,
+    receiver: Receiver<(SocketAddr, Bytes)>,
+    connections: HashMap<SocketAddr, (Sender<Bytes>, PeerHandle)>,
+}
+
+impl Server {
+    /// Binds the UDP socket of `host_addr` and spawns the accepting task. Port 0 binds an
+    /// ephemeral port, the actual address is reported by [`Handle::local_addr`].
+    pub async fn spawn(host_addr: SocketAddr, options: Options) -> io::Result<(Channel, Handle)> {
+        let endpoint = Endpoint::server(server_config(&options)?, host_addr)?;
+        let local_addr = endpoint.local_addr()?;
+        let (sender, ret_receiver) = channel(1000);
+        let (ret_sender, receiver) = channel(1000);
+        let handle = Handle::new(Some(local_addr));
+        let mut server = Self {
+            endpoint,
+            options,
+            stats: handle.stats.clone(),
+            cancel: handle.cancel.clone(),
+            tasks: handle.tasks.clone(),
+            sender,
+            receiver,
+            connections: Default::default(),
+        };
+        handle.tasks.spawn(async move { server.run().await });
+        Ok(((ret_sender, ret_receiver), handle))
+    }
+
+    async fn run(&mut self) {
+        loop {
+            tokio::select! {
+                Some(connecting) = self.endpoint.accept() => self.accept(connecting),
+                Some((addr, msg)) = self.receiver.recv() => {
+                    match self.connections.entry(addr) {
+                        Entry::Occupied(mut entry) => {
+                            trace!("sending msg to {}", addr);
+                            let (sender, peer) = entry.get_mut();
+                            peer.record_queued();
+                            if let Err(e) = sender.send(msg).await {
+                                warn!("Disconnectted from {}: {}", addr, e);
+                                peer.record_dropped(1);
+                                entry.remove();
+                            }
+                        }
+                        Entry::Vacant(_) => {
+                            warn!("No connection from {}", addr);
+                            self.stats.record_dropped(1);
+                        }
+                    }
+                }
+                () = self.cancel.cancelled() => break,
+            }
+        }
+        trace!("stop listening on {:?}", self.endpoint.local_addr());
+        self.endpoint.close(VarInt::from_u32(0), b"shutdown");
+        self.endpoint.wait_idle().await;
+    }
+
+    fn accept(&mut self, connecting: Connecting) {
+        let remote_addr = connecting.remote_address();
+        trace!("accept connection from {}", remote_addr);
+        let (sender, mut receiver) = channel(1000);
+        let peer = self.stats.peer_counters(remote_addr);
+        self.connections.insert(remote_addr, (sender, peer.clone()));
+        let link = Link {
+            remote_addr,
+            options: self.options,
+            peer,
+            sender: self.sender.clone(),
+            tasks: self.tasks.clone(),
+            cancel: self.cancel.clone(),
+        };
+        self.tasks.spawn(async move {
+            link.peer.record_connected(true);
+            let result = match connecting.await {
+                Ok(connection) => link.serve(&connection, &mut receiver).await,
+                Err(e) => {
+                    if is_rejected(&e) {
+                        link.peer.stats().record_rejected();
+                    }
+                    Err(e.into())
+                }
+            };
+            if let Err(e) = result {
+                warn!("Disconnectted from {}: {}", remote_addr, e);
+            }
+            link.peer.stats().remove_peer(&remote_addr);
+        });
+    }
+}
+
+/// Moves the messages of an established connection in both directions.
+struct Link {
+    remote_addr: SocketAddr,
+    options: Options,
+    peer: PeerHandle,
+    sender: Sender<(SocketAddr, Bytes)>,
+    tasks: TaskTracker,
+    cancel: CancellationToken,
+}
+
+impl Link {
+    /// Returns once the connection is lost or cancelled.
+    async fn serve(
+        &self,
+        connection: &quinn::Connection,
+        outgoing: &mut Receiver<Bytes>,
+    ) -> Result<(), Error> {
+        let (incoming_sender, mut incoming) = channel(100);
+        let mut limiter = RateLimiter::new(self.options.frame_limits);
+        let mut rtt = time::interval(self.options.heartbeat.interval);
+        // Streams are neither accepted nor delivered until the timer fires once the decode
+        // budget is exhausted, streams already open wait on the full channel.
+        let resume = time::sleep_until(Instant::now());
+        tokio::pin!(resume);
+        let mut throttled = false;
+        loop {
+            tokio::select! {
+                () = self.cancel.cancelled() => {
+                    connection.close(VarInt::from_u32(0), b"shutdown");
+                    return Ok(());
+                }
+                Some(data) = outgoing.recv() => self.write(connection, data),
+                stream = connection.accept_uni(), if !throttled => {
+                    self.read(stream?, incoming_sender.clone());
+                }
+                Some(data) = incoming.recv(), if !throttled => {
+                    let data: Bytes = data?;
+                    self.peer.record_seen();
+                    self.peer.record_received(data.len());
+                    if let Some(until) = limiter.check(data.len()) {
+                        trace!("throttle reading from {}", self.remote_addr);
+                        self.peer.stats().record_throttled();
+                        resume.as_mut().reset(until);
+                        throttled = true;
+                    }
+                    self.sender.send((self.remote_addr, data)).await.unwrap();
+                }
+                () = &mut resume, if throttled => throttled = false,
+                _ = rtt.tick() => {
+                    // QUIC acknowledges every packet, a live connection has been heard from
+                    // within the idle timeout.
+                    self.peer.record_seen();
+                    self.peer.record_rtt(connection.rtt());
+                }
+            }
+        }
+    }
+
+    /// Sends `data` on a new stream, a message that fails to be written is lost.
+    fn write(&self, connection: &quinn::Connection, data: Bytes) {
+        let connection = connection.clone();
+        let peer = self.peer.clone();
+        self.tasks.spawn(async move {
+            let len = data.len();
+            let result = async {
+                let mut stream = connection.open_uni().await?;
+                stream.write_all(&data).await?;
+                stream.finish().await?;
+                Ok::<_, Error>(())
+            }
+            .await;
+            match result {
+                Ok(()) => peer.record_sent(len),
+                Err(e) => {
+                    trace!("write to {} failed: {}", connection.remote_address(), e);
+                    peer.record_dropped(1);
+                }
+            }
+        });
+    }
+
+    /// Reads a whole stream as a single message.
+    fn read(&self, mut stream: RecvStream, incoming: Sender<Result<Bytes, Error>>) {
+        let max_frame_length = self.options.frame_limits.max_frame_length;
+        let stats = self.peer.stats().clone();
+        self.tasks.spawn(async move {
+            let data = match stream.read_to_end(max_frame_length).await {
+                Ok(data) => Ok(data.into()),
+                Err(ReadToEndError::TooLong) => {
+                    stats.record_oversized_frame();
+                    Err(Error::new(ErrorKind::InvalidData, "frame size too big"))
+                }
+                Err(ReadToEndError::Read(e)) => Err(e.into()),
+            };
+            let _ = incoming.send(data).await;
+        });
+    }
+}
+
+fn client_endpoint(bind_addr: SocketAddr, options: &Options) -> io::Result<Endpoint> {
+    let mut endpoint = Endpoint::client(bind_addr)?;
+    endpoint.set_default_client_config(client_config(options));
+    Ok(endpoint)
+}
+
+fn transport_config(options: &Options) -> Arc<TransportConfig> {
+    let heartbeat = options.heartbeat;
+    let mut config = TransportConfig::default();
+    config.keep_alive_interval(Some(heartbeat.interval));
+    config.max_idle_timeout(IdleTimeout::try_from(heartbeat.interval * heartbeat.max_missed).ok());
+    Arc::new(config)
+}
+
+fn server_config(options: &Options) -> io::Result<ServerConfig> {
+    let cert =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).map_err(Error::other)?;
+    let cert_der = cert.serialize_der().map_err(Error::other)?;
+    let key = PrivateKey(cert.serialize_private_key_der());
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(vec![Certificate(cert_der)], key)
+        .map_err(Error::other)?;
+    crypto.alpn_protocols = alpn_protocols(options);
+    let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config(options));
+    Ok(config)
+}
+
+fn client_config(options: &Options) -> ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
+        .with_no_client_auth();
+    crypto.alpn_protocols = alpn_protocols(options);
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config(options));
+    config
+}
+
+/// The network id is negotiated as the application protocol, so that the TLS handshake of
+/// a client with another id fails.
+fn alpn_protocols(options: &Options) -> Vec<Vec<u8>> {
+    options.network_id.iter().map(|id| id.to_vec()).collect()
+}
+
+/// Whether a connection failed for offering no application protocol the server speaks.
+fn is_rejected(e: &ConnectionError) -> bool {
+    const NO_APPLICATION_PROTOCOL: u64 = 0x100 | 120;
+    match e {
+        ConnectionError::TransportError(e) => u64::from(e.code) == NO_APPLICATION_PROTOCOL,
+        ConnectionError::ConnectionClosed(close) => {
+            u64::from(close.error_code) == NO_APPLICATION_PROTOCOL
+        }
+        _ => false,
+    }
+}
+
+struct SkipServerVerification;
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod quic_tests {
+    use super::*;
+    use crate::FrameLimits;
+
+    #[tokio::test]
+    async fn round_trip_test() {
+        // Both address families are dialed from the same client.
+        let ((client_sender, mut client_receiver), client_handle) =
+            Client::spawn(Options::default()).unwrap();
+        for host_addr in ["127.0.0.1:0", "[::1]:0"] {
+            round_trip(
+                host_addr.parse().unwrap(),
+                &client_sender,
+                &mut client_receiver,
+            )
+            .await;
+        }
+        time::timeout(Duration::from_secs(5), client_handle.shutdown())
+            .await
+            .unwrap();
+    }
+
+    async fn round_trip(
+        host_addr: SocketAddr,
+        client_sender: &Sender<(SocketAddr, Bytes)>,
+        client_receiver: &mut Receiver<(SocketAddr, Bytes)>,
+    ) {
+        let ((sender, mut receiver), handle) =
+            Server::spawn(host_addr, Options::default()).await.unwrap();
+        let local_addr = handle.local_addr().unwrap();
+
+        // A large message does not block the small one sent after it.
+        client_sender
+            .send((local_addr, Bytes::from(vec![1; 1024 * 1024])))
+            .await
+            .unwrap();
+        client_sender
+            .send((local_addr, Bytes::from_static(b"hello")))
+            .await
+            .unwrap();
+        let mut received = vec![];
+        for _ in 0..2 {
+            received.push(receiver.recv().await.unwrap());
+        }
+        assert!(received.iter().any(|(_, data)| data.as_ref() == b"hello"));
+        assert!(received.iter().any(|(_, data)| data.len() == 1024 * 1024));
+
+        let remote_addr = received[0].0;
+        sender
+            .send((remote_addr, Bytes::from_static(b"world")))
+            .await
+            .unwrap();
+        let (from, data) = client_receiver.recv().await.unwrap();
+        assert_eq!(from, local_addr);
+        assert_eq!(data.as_ref(), b"world");
+        time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_test() {
+        let options = Options {
+            frame_limits: FrameLimits {
+                max_frame_length: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let ((_sender, mut receiver), handle) =
+            Server::spawn("127.0.0.1:0".parse().unwrap(), options)
+                .await
+                .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+        let ((client_sender, _client_receiver), _client_handle) =
+            Client::spawn(Options::default()).unwrap();
+        client_sender
+            .send((local_addr, Bytes::from(vec![0; 64])))
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if handle.stats().oversized_frames() > 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(handle.stats().oversized_frames(), 1);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn throttle_test() {
+        let options = Options {
+            frame_limits: FrameLimits {
+                max_frames_per_sec: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let ((_sender, mut receiver), handle) =
+            Server::spawn("127.0.0.1:0".parse().unwrap(), options)
+                .await
+                .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+        let ((client_sender, _client_receiver), _client_handle) =
+            Client::spawn(Options::default()).unwrap();
+        for n in 0..10u8 {
+            client_sender
+                .send((local_addr, Bytes::from(vec![n])))
+                .await
+                .unwrap();
+        }
+
+        // Messages on streams already open are held back too until the next second.
+        time::sleep(Duration::from_millis(500)).await;
+        let mut received = 0;
+        while receiver.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 2);
+        assert_eq!(handle.stats().throttled(), 1);
+    }
+}
@@ -1,6 +1,11 @@
-use crate::common::{Channel, Reader, Writer};
+use crate::common::{
+    is_oversized, Channel, Frame, FrameCodec, Handle, Liveness, Options, RateLimiter, Reader,
+    Writer,
+};
+use crate::stats::{PeerHandle, Stats};
 
 use std::collections::{hash_map::Entry, HashMap};
+use std::io::{self, Error, ErrorKind};
 use std::net::SocketAddr;
 
 use bytes::Bytes;
@@ -9,46 +14,64 @@ use log::{error, trace, warn};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::mpsc::{channel, Receiver, Sender},
+    time::{self, Instant},
 };
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::{codec::Framed, sync::CancellationToken, task::TaskTracker};
 
 pub struct Server {
-    host_addr: SocketAddr,
+    listener: TcpListener,
+    options: Options,
+    stats: Stats,
+    cancel: CancellationToken,
+    tasks: TaskTracker,
     sender: Sender<(SocketAddr, Bytes)>,
     receiver: Receiver<(SocketAddr, Bytes)>,
-    connections: HashMap<SocketAddr, Sender<(SocketAddr, Bytes)>>,
+    connections: HashMap<SocketAddr, (Sender<(SocketAddr, Bytes)>, PeerHandle)>,
 }
 
 impl Server {
-    pub fn spawn(host_addr: SocketAddr) -> Channel {
+    /// Binds `host_addr` and spawns the accepting task. Port 0 binds an ephemeral port,
+    /// the actual address is reported by [`Handle::local_addr`].
+    pub async fn spawn(host_addr: SocketAddr, options: Options) -> io::Result<(Channel, Handle)> {
+        let listener = TcpListener::bind(host_addr).await?;
+        let local_addr = listener.local_addr()?;
         let (sender, ret_receiver) = channel(1000);
         let (ret_sender, receiver) = channel(1000);
-        tokio::spawn(async move {
-            Self {
-                host_addr,
-                sender,
-                receiver,
-                connections: Default::default(),
-            }
-            .run()
-            .await;
-        });
-        (ret_sender, ret_receiver)
+        let handle = Handle::new(Some(local_addr));
+        let mut server = Self {
+            listener,
+            options,
+            stats: handle.stats.clone(),
+            cancel: handle.cancel.clone(),
+            tasks: handle.tasks.clone(),
+            sender,
+            receiver,
+            connections: Default::default(),
+        };
+        handle.tasks.spawn(async move { server.run().await });
+        Ok(((ret_sender, ret_receiver), handle))
     }
 
     async fn run(&mut self) {
-        let listener = TcpListener::bind(self.host_addr)
-            .await
-            .expect("Failed to bind TCP port!");
         loop {
             tokio::select! {
-                connection = listener.accept() => {
+                connection = self.listener.accept() => {
                     match connection {
                         Ok((socket, addr)) => {
                             trace!("accept connection from {}", addr);
                             let (sender, receiver) = channel(1000);
-                            self.connections.insert(addr, sender);
-                            Connection::spawn(addr, socket, self.sender.clone(), receiver);
+                            let peer = self.stats.peer_counters(addr);
+                            self.connections.insert(addr, (sender, peer.clone()));
+                            let mut connection = Connection::new(
+                                addr,
+                                socket,
+                                self.options,
+                                peer,
+                                self.sender.clone(),
+                                receiver,
+                                self.cancel.clone(),
+                            );
+                            self.tasks.spawn(async move { connection.run().await });
                         }
                         Err(e) => error!("couldn't get client: {e:?}"),
                     }
@@ -57,14 +80,24 @@ impl Server {
                     match  self.connections.entry(addr) {
                         Entry::Occupied(mut entry) => {
                             trace!("sending msg to {}", addr);
-                            if let Err(e) = entry.get_mut().send((addr, msg)).await {
+                            let (sender, peer) = entry.get_mut();
+                            peer.record_queued();
+                            if let Err(e) = sender.send((addr, msg)).await {
                                 warn!("Disconnectted from {}: {}", addr, e);
+                                peer.record_dropped(1);
                                 entry.remove();
                             }
                         }
-                        Entry::Vacant(_) => warn!("No connection from {}", addr),
+                        Entry::Vacant(_) => {
+                            warn!("No connection from {}", addr);
+                            self.stats.record_dropped(1);
+                        }
                     }
                 }
+                () = self.cancel.cancelled() => {
+                    trace!("stop listening on {:?}", self.listener.local_addr());
+                    break;
+                }
             }
         }
     }
@@ -76,52 +109,380 @@ struct Connection {
     remote_addr: SocketAddr,
     reader: Reader,
     writer: Writer,
+    limiter: RateLimiter,
+    liveness: Liveness,
+    options: Options,
+    peer: PeerHandle,
+    cancel: CancellationToken,
 }
 
 impl Connection {
-    fn spawn(
+    fn new(
         remote_addr: SocketAddr,
         socket: TcpStream,
+        options: Options,
+        peer: PeerHandle,
         sender: Sender<(SocketAddr, Bytes)>,
         receiver: Receiver<(SocketAddr, Bytes)>,
-    ) {
-        let (writer, reader) = Framed::new(socket, LengthDelimitedCodec::new()).split();
-        tokio::spawn(async move {
-            Self {
-                sender,
-                receiver,
-                remote_addr,
-                reader,
-                writer,
+        cancel: CancellationToken,
+    ) -> Self {
+        let codec = FrameCodec::new(&options.frame_limits);
+        let (writer, reader) = Framed::new(socket, codec).split();
+        Self {
+            sender,
+            receiver,
+            remote_addr,
+            reader,
+            writer,
+            limiter: RateLimiter::new(options.frame_limits),
+            liveness: Liveness::new(options.heartbeat),
+            options,
+            peer,
+            cancel,
+        }
+    }
+
+    async fn run(&mut self) {
+        self.peer.record_connected(true);
+        match self.serve().await {
+            Err(e) if is_oversized(&e) => {
+                warn!(
+                    "Oversized frame from {}, disconnect: {}",
+                    self.remote_addr, e
+                );
+                self.peer.stats().record_oversized_frame();
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                trace!("connection closed by {}", self.remote_addr);
             }
-            .run()
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                warn!("Rejected connection from {}: {}", self.remote_addr, e);
+                self.peer.stats().record_rejected();
+            }
+            Err(e) => warn!("Disconnectted from {}: {}", self.remote_addr, e),
+            Ok(()) => trace!("close connection from {}", self.remote_addr),
+        }
+        self.peer.stats().remove_peer(&self.remote_addr);
+    }
+
+    /// Expects the client's network id as the first frame, when the server has one.
+    async fn handshake(&mut self) -> Result<(), Error> {
+        let Some(network_id) = self.options.network_id else {
+            return Ok(());
+        };
+        let timeout = self.options.heartbeat.interval * self.options.heartbeat.max_missed;
+        let frame = time::timeout(timeout, self.reader.next())
             .await
-        });
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "no handshake"))?
+            .ok_or(ErrorKind::UnexpectedEof)??;
+        match frame {
+            Frame::Hello(id) if id == network_id => Ok(()),
+            Frame::Hello(_) => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "network id mismatch",
+            )),
+            _ => Err(Error::new(ErrorKind::PermissionDenied, "no network id")),
+        }
     }
 
-    async fn run(&mut self) {
+    async fn serve(&mut self) -> Result<(), Error> {
+        let cancel = self.cancel.clone();
+        tokio::select! {
+            () = cancel.cancelled() => return Ok(()),
+            result = self.handshake() => result?,
+        }
+        let mut heartbeat = time::interval(self.options.heartbeat.interval);
+        // Reading is paused until the timer fires once the decode budget is exhausted.
+        let resume = time::sleep_until(Instant::now());
+        tokio::pin!(resume);
+        let mut throttled = false;
         loop {
             tokio::select! {
-                Some(framed_data) = self.reader.next() => {
-                    match framed_data {
-                        Ok(data) => {
+                () = self.cancel.cancelled() => return Ok(()),
+                () = &mut resume, if throttled => throttled = false,
+                frame = self.reader.next(), if !throttled => {
+                    let frame = frame.ok_or(ErrorKind::UnexpectedEof)??;
+                    self.peer.record_seen();
+                    match frame {
+                        Frame::Data(data) => {
                             trace!("received msg from: {}", self.remote_addr,);
-                            self.sender
-                                .send((self.remote_addr, data.freeze()))
-                                .await
-                                .unwrap()
+                            self.peer.record_received(data.len());
+                            if let Some(until) = self.limiter.check(data.len()) {
+                                trace!("throttle reading from {}", self.remote_addr);
+                                self.peer.stats().record_throttled();
+                                resume.as_mut().reset(until);
+                                throttled = true;
+                            }
+                            self.sender.send((self.remote_addr, data)).await.unwrap()
+                        }
+                        Frame::Ping(nonce) => self.writer.send(Frame::Pong(nonce)).await?,
+                        Frame::Pong(nonce) => {
+                            if let Some(rtt) = self.liveness.pong(nonce) {
+                                self.peer.record_rtt(rtt);
+                            }
                         }
-                        Err(e) => error!("{}", e),
-                    };
+                        Frame::Hello(_) => (),
+                    }
                 },
+                _ = heartbeat.tick() => {
+                    let ping = self.liveness.tick()?;
+                    self.peer.record_missed_heartbeats(self.liveness.missed());
+                    self.writer.send(ping).await?;
+                }
                 Some((addr, data)) = self.receiver.recv() => {
                     trace!("sending msg to {}", addr);
-                    if let Err(e) = self.writer.send(data).await {
-                        warn!("Disconnectted from {}: {}", self.remote_addr, e);
-                        return ;
+                    let len = data.len();
+                    if let Err(e) = self.writer.send(Frame::Data(data)).await {
+                        self.peer.record_dropped(1);
+                        return Err(e);
                     }
+                    self.peer.record_sent(len);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::{FrameLimits, Heartbeat};
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn bind_ephemeral_port_test() {
+        let ((_sender, mut receiver), handle) =
+            Server::spawn("127.0.0.1:0".parse().unwrap(), Options::default())
+                .await
+                .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+        assert_ne!(local_addr.port(), 0);
+        assert!(Server::spawn(local_addr, Options::default()).await.is_err());
+
+        let ((client_sender, _client_receiver), _) = Client::spawn(Options::default());
+        client_sender
+            .send((local_addr, Bytes::from_static(b"hello")))
+            .await
+            .unwrap();
+        let (_, data) = receiver.recv().await.unwrap();
+        assert_eq!(data.as_ref(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_test() {
+        let limits = Options {
+            frame_limits: FrameLimits {
+                max_frame_length: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let ((_sender, mut receiver), handle) =
+            Server::spawn("127.0.0.1:0".parse().unwrap(), limits)
+                .await
+                .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+
+        let ((client_sender, _client_receiver), _) = Client::spawn(Options::default());
+        client_sender
+            .send((local_addr, Bytes::from(vec![0; 64])))
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if handle.stats().oversized_frames() > 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(handle.stats().oversized_frames(), 1);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn heartbeat_test() {
+        let options = Options {
+            heartbeat: Heartbeat {
+                interval: Duration::from_millis(20),
+                max_missed: 3,
+            },
+            ..Default::default()
+        };
+        let ((_sender, _receiver), handle) = Server::spawn("127.0.0.1:0".parse().unwrap(), options)
+            .await
+            .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+
+        let ((client_sender, _client_receiver), client_handle) = Client::spawn(options);
+        client_sender
+            .send((local_addr, Bytes::from_static(b"hello")))
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if client_handle
+                .stats()
+                .peer(&local_addr)
+                .is_some_and(|peer| peer.rtt.is_some())
+            {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        let peer = client_handle.stats().peer(&local_addr).unwrap();
+        assert!(peer.connected);
+        assert!(peer.rtt.is_some());
+    }
+
+    #[tokio::test]
+    async fn traffic_stats_test() {
+        let ((sender, mut receiver), handle) =
+            Server::spawn("127.0.0.1:0".parse().unwrap(), Options::default())
+                .await
+                .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+
+        let ((client_sender, mut client_receiver), client_handle) =
+            Client::spawn(Options::default());
+        for _ in 0..3 {
+            client_sender
+                .send((local_addr, Bytes::from_static(b"hello")))
+                .await
+                .unwrap();
+        }
+        let mut remote_addr = None;
+        for _ in 0..3 {
+            remote_addr = Some(receiver.recv().await.unwrap().0);
+        }
+        let remote_addr = remote_addr.unwrap();
+        sender
+            .send((remote_addr, Bytes::from_static(b"world")))
+            .await
+            .unwrap();
+        client_receiver.recv().await.unwrap();
+
+        let client_peer = client_handle.stats().peer(&local_addr).unwrap();
+        assert_eq!(client_peer.messages_sent, 3);
+        assert_eq!(client_peer.bytes_sent, 15);
+        assert_eq!(client_peer.messages_received, 1);
+        assert_eq!(client_peer.queued, 0);
+        assert!(client_peer.last_seen.is_some());
+        let server_peer = handle.stats().peer(&remote_addr).unwrap();
+        assert_eq!(server_peer.messages_received, 3);
+        assert_eq!(server_peer.bytes_received, 15);
+        assert_eq!(server_peer.messages_sent, 1);
+
+        sender
+            .send(("127.0.0.1:1".parse().unwrap(), Bytes::from_static(b"lost")))
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if handle.stats().dropped() > 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(handle.stats().dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_test() {
+        let ((_sender, mut receiver), handle) =
+            Server::spawn("127.0.0.1:0".parse().unwrap(), Options::default())
+                .await
+                .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+        let ((client_sender, _client_receiver), client_handle) = Client::spawn(Options::default());
+        client_sender
+            .send((local_addr, Bytes::from_static(b"hello")))
+            .await
+            .unwrap();
+        receiver.recv().await.unwrap();
+
+        time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .unwrap();
+        assert!(handle.is_shutdown());
+        assert!(receiver.recv().await.is_none());
+        // The listening socket is released.
+        Server::spawn(local_addr, Options::default()).await.unwrap();
+
+        time::timeout(Duration::from_secs(5), client_handle.shutdown())
+            .await
+            .unwrap();
+        assert!(!client_handle.stats().peer(&local_addr).unwrap().connected);
+    }
+
+    #[tokio::test]
+    async fn disconnect_test() {
+        let ((_sender, mut receiver), handle) =
+            Server::spawn("127.0.0.1:0".parse().unwrap(), Options::default())
+                .await
+                .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+        let ((client_sender, _client_receiver), client_handle) = Client::spawn(Options::default());
+        client_sender
+            .send((local_addr, Bytes::from_static(b"hello")))
+            .await
+            .unwrap();
+        let (first_addr, _) = receiver.recv().await.unwrap();
+
+        client_handle.disconnect(local_addr);
+        for _ in 0..50 {
+            if handle.stats().peer(&first_addr).is_none() {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(handle.stats().peer(&first_addr).is_none());
+        assert!(client_handle.stats().peer(&local_addr).is_none());
+
+        // Sending again reconnects.
+        client_sender
+            .send((local_addr, Bytes::from_static(b"again")))
+            .await
+            .unwrap();
+        let (second_addr, data) = receiver.recv().await.unwrap();
+        assert_ne!(first_addr, second_addr);
+        assert_eq!(data, Bytes::from_static(b"again"));
+    }
+
+    #[tokio::test]
+    async fn handshake_test() {
+        let options = Options {
+            network_id: Some([1; 32]),
+            ..Default::default()
+        };
+        let ((_sender, mut receiver), handle) =
+            Server::spawn("127.0.0.1:0".parse().unwrap(), options)
+                .await
+                .unwrap();
+        let local_addr = handle.local_addr().unwrap();
+
+        let foreign = Options {
+            network_id: Some([2; 32]),
+            ..Default::default()
+        };
+        let ((client_sender, _client_receiver), _) = Client::spawn(foreign);
+        client_sender
+            .send((local_addr, Bytes::from_static(b"foreign")))
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if handle.stats().rejected() > 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        // The client keeps reconnecting, every attempt is rejected.
+        assert!(handle.stats().rejected() > 0);
+
+        let ((client_sender, _client_receiver), _) = Client::spawn(options);
+        client_sender
+            .send((local_addr, Bytes::from_static(b"hello")))
+            .await
+            .unwrap();
+        let (_, data) = receiver.recv().await.unwrap();
+        assert_eq!(data.as_ref(), b"hello");
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counters shared by all connections of a [`Client`](crate::client::Client) or
+/// [`Server`](crate::server::Server).
+#[derive(Clone)]
+pub struct Stats(Arc<Counters>);
+
+struct Counters {
+    epoch: Instant,
+    oversized_frames: AtomicU64,
+    throttled: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+    peers: Mutex<HashMap<SocketAddr, Arc<PeerCounters>>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self(Arc::new(Counters {
+            epoch: Instant::now(),
+            oversized_frames: Default::default(),
+            throttled: Default::default(),
+            dropped: Default::default(),
+            rejected: Default::default(),
+            peers: Default::default(),
+        }))
+    }
+}
+
+/// Counters of a single remote, updated by its connection without locking.
+#[derive(Default)]
+pub(crate) struct PeerCounters {
+    connected: AtomicBool,
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    reconnects: AtomicU64,
+    queued: AtomicU64,
+    dropped: AtomicU64,
+    missed_heartbeats: AtomicU64,
+    // Microseconds, 0 until the first sample.
+    rtt: AtomicU64,
+    // Microseconds since `Counters::epoch`, 0 if never seen.
+    last_seen: AtomicU64,
+}
+
+/// Snapshot of the traffic exchanged with a single remote.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStats {
+    /// Whether a connection to the remote is currently established.
+    pub connected: bool,
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// Connections re-established after the first one.
+    pub reconnects: u64,
+    /// Messages accepted for the remote but not written to the socket yet.
+    pub queued: u64,
+    /// Messages for the remote that were discarded.
+    pub dropped: u64,
+    /// Smoothed round trip time, measured by heartbeats.
+    pub rtt: Option<Duration>,
+    /// Pings that went unanswered since the last pong.
+    pub missed_heartbeats: u32,
+    /// When the last frame of the remote was received.
+    pub last_seen: Option<Instant>,
+}
+
+/// Snapshot of a [`Stats`].
+#[derive(Debug, Clone, Default)]
+pub struct StatsSnapshot {
+    pub oversized_frames: u64,
+    pub throttled: u64,
+    pub dropped: u64,
+    pub rejected: u64,
+    pub peers: HashMap<SocketAddr, PeerStats>,
+}
+
+impl Stats {
+    /// Number of frames rejected for exceeding `max_frame_length`.
+    pub fn oversized_frames(&self) -> u64 {
+        self.0.oversized_frames.load(Ordering::Relaxed)
+    }
+
+    /// Number of times a connection exhausted its per-second decode budget.
+    pub fn throttled(&self) -> u64 {
+        self.0.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages discarded, including those for unknown remotes.
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections refused for presenting another network id.
+    pub fn rejected(&self) -> u64 {
+        self.0.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn peer(&self, addr: &SocketAddr) -> Option<PeerStats> {
+        let peers = self.0.peers.lock().unwrap();
+        peers.get(addr).map(|counters| self.load(counters))
+    }
+
+    pub fn peers(&self) -> HashMap<SocketAddr, PeerStats> {
+        let peers = self.0.peers.lock().unwrap();
+        peers
+            .iter()
+            .map(|(addr, counters)| (*addr, self.load(counters)))
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            oversized_frames: self.oversized_frames(),
+            throttled: self.throttled(),
+            dropped: self.dropped(),
+            rejected: self.rejected(),
+            peers: self.peers(),
+        }
+    }
+
+    fn load(&self, counters: &PeerCounters) -> PeerStats {
+        let rtt = counters.rtt.load(Ordering::Relaxed);
+        let last_seen = counters.last_seen.load(Ordering::Relaxed);
+        PeerStats {
+            connected: counters.connected.load(Ordering::Relaxed),
+            messages_sent: counters.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+            messages_received: counters.messages_received.load(Ordering::Relaxed),
+            bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+            reconnects: counters.reconnects.load(Ordering::Relaxed),
+            queued: counters.queued.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            missed_heartbeats: counters.missed_heartbeats.load(Ordering::Relaxed) as u32,
+            rtt: (rtt != 0).then(|| Duration::from_micros(rtt)),
+            last_seen: (last_seen != 0).then(|| self.0.epoch + Duration::from_micros(last_seen)),
+        }
+    }
+
+    /// Counters of `addr`, created on first use.
+    pub(crate) fn peer_counters(&self, addr: SocketAddr) -> PeerHandle {
+        let counters = self
+            .0
+            .peers
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .clone();
+        PeerHandle {
+            stats: self.clone(),
+            counters,
+        }
+    }
+
+    pub(crate) fn record_oversized_frame(&self) {
+        self.0.oversized_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_throttled(&self) {
+        self.0.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped(&self, count: u64) {
+        self.0.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rejected(&self) {
+        self.0.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Forgets a remote, used by servers once a connection is gone since the remote
+    /// address is not reused, and by clients told to disconnect.
+    pub(crate) fn remove_peer(&self, addr: &SocketAddr) {
+        self.0.peers.lock().unwrap().remove(addr);
+    }
+}
+
+/// Updates the counters of a single remote.
+#[derive(Clone)]
+pub(crate) struct PeerHandle {
+    stats: Stats,
+    counters: Arc<PeerCounters>,
+}
+
+impl PeerHandle {
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub(crate) fn record_connected(&self, connected: bool) {
+        let was_connected = self.counters.connected.swap(connected, Ordering::Relaxed);
+        self.counters.missed_heartbeats.store(0, Ordering::Relaxed);
+        if connected && !was_connected && self.counters.last_seen.load(Ordering::Relaxed) != 0 {
+            self.counters.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        if connected {
+            self.record_seen();
+        }
+    }
+
+    pub(crate) fn record_queued(&self) {
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_sent(&self, len: usize) {
+        self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+        self.counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_sent
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped(&self, count: u64) {
+        self.counters.queued.fetch_sub(count, Ordering::Relaxed);
+        self.counters.dropped.fetch_add(count, Ordering::Relaxed);
+        self.stats.record_dropped(count);
+    }
+
+    pub(crate) fn record_received(&self, len: usize) {
+        self.counters
+            .messages_received
+            .fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_received
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Called on every frame received from the remote.
+    pub(crate) fn record_seen(&self) {
+        let since_epoch = self.stats.0.epoch.elapsed().as_micros().max(1) as u64;
+        self.counters
+            .last_seen
+            .store(since_epoch, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_missed_heartbeats(&self, missed: u32) {
+        self.counters
+            .missed_heartbeats
+            .store(missed as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rtt(&self, sample: Duration) {
+        self.counters.missed_heartbeats.store(0, Ordering::Relaxed);
+        let sample = sample.as_micros().max(1) as u64;
+        let rtt = self.counters.rtt.load(Ordering::Relaxed);
+        // Exponentially weighted like the smoothed RTT of TCP.
+        let rtt = if rtt == 0 {
+            sample
+        } else {
+            (rtt * 7 + sample) / 8
+        };
+        self.counters.rtt.store(rtt.max(1), Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, sent {} msgs/{} bytes, received {} msgs/{} bytes, rtt {:?}, \
+             reconnects {}, queued {}, dropped {}, last seen {:?} ago",
+            if self.connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            self.messages_sent,
+            self.bytes_sent,
+            self.messages_received,
+            self.bytes_received,
+            self.rtt,
+            self.reconnects,
+            self.queued,
+            self.dropped,
+            self.last_seen.map(|last_seen| last_seen.elapsed()),
+        )
+    }
+}
@@ -0,0 +1,161 @@
+use crate::common::Channel;
+
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use bytes::Bytes;
+use tokio::sync::mpsc::{error::TryRecvError, Receiver, Sender};
+
+/// Turns messages into frames and back.
+pub trait Codec<T>: Clone + Send + 'static {
+    fn encode(&self, msg: &T) -> io::Result<Bytes>;
+    fn decode(&self, data: &[u8]) -> io::Result<T>;
+}
+
+/// The default codec, frames are the Borsh encoding of the message.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BorshCodec;
+
+impl<T: BorshSerialize + BorshDeserialize> Codec<T> for BorshCodec {
+    fn encode(&self, msg: &T) -> io::Result<Bytes> {
+        Ok(msg.try_to_vec()?.into())
+    }
+
+    fn decode(&self, mut data: &[u8]) -> io::Result<T> {
+        T::deserialize(&mut data)
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    Encode(io::Error),
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Encode(e) => write!(f, "encode message failed: {}", e),
+            SendError::Closed => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Sending half of a typed [`Channel`].
+pub struct TypedSender<T, C = BorshCodec> {
+    sender: Sender<(SocketAddr, Bytes)>,
+    codec: C,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, C: Clone> Clone for TypedSender<T, C> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            codec: self.codec.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, C: Codec<T>> TypedSender<T, C> {
+    pub async fn send(&self, addr: SocketAddr, msg: &T) -> Result<(), SendError> {
+        let data = self.codec.encode(msg).map_err(SendError::Encode)?;
+        self.sender
+            .send((addr, data))
+            .await
+            .map_err(|_| SendError::Closed)
+    }
+
+    /// Like [`TypedSender::send`], for callers outside of the tokio runtime.
+    pub fn blocking_send(&self, addr: SocketAddr, msg: &T) -> Result<(), SendError> {
+        let data = self.codec.encode(msg).map_err(SendError::Encode)?;
+        self.sender
+            .blocking_send((addr, data))
+            .map_err(|_| SendError::Closed)
+    }
+}
+
+/// Receiving half of a typed [`Channel`]. Frames that fail to decode are yielded as errors
+/// along with the address they came from.
+pub struct TypedReceiver<T, C = BorshCodec> {
+    receiver: Receiver<(SocketAddr, Bytes)>,
+    codec: C,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, C: Codec<T>> TypedReceiver<T, C> {
+    pub async fn recv(&mut self) -> Option<(SocketAddr, io::Result<T>)> {
+        let (addr, data) = self.receiver.recv().await?;
+        Some((addr, self.codec.decode(&data)))
+    }
+
+    pub fn try_recv(&mut self) -> Result<(SocketAddr, io::Result<T>), TryRecvError> {
+        let (addr, data) = self.receiver.try_recv()?;
+        Ok((addr, self.codec.decode(&data)))
+    }
+}
+
+/// Wraps a raw channel, sending `S` and receiving `R` encoded with [`BorshCodec`].
+pub fn typed<S, R>(channel: Channel) -> (TypedSender<S>, TypedReceiver<R>)
+where
+    S: BorshSerialize + BorshDeserialize,
+    R: BorshSerialize + BorshDeserialize,
+{
+    typed_with_codec(channel, BorshCodec)
+}
+
+pub fn typed_with_codec<S, R, C>(
+    (sender, receiver): Channel,
+    codec: C,
+) -> (TypedSender<S, C>, TypedReceiver<R, C>)
+where
+    C: Codec<S> + Codec<R>,
+{
+    (
+        TypedSender {
+            sender,
+            codec: codec.clone(),
+            _marker: PhantomData,
+        },
+        TypedReceiver {
+            receiver,
+            codec,
+            _marker: PhantomData,
+        },
+    )
+}
+
+#[cfg(test)]
+mod typed_tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn decode_error_test() {
+        let (raw_sender, receiver) = channel(10);
+        let (sender, mut raw_receiver) = channel(10);
+        let (typed_sender, mut typed_receiver) =
+            typed::<(u32, String), (u32, String)>((sender, receiver));
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        typed_sender.send(addr, &(7, "seven".into())).await.unwrap();
+        let frame = raw_receiver.recv().await.unwrap();
+        raw_sender.send(frame).await.unwrap();
+        raw_sender
+            .send((addr, Bytes::from_static(&[1, 2])))
+            .await
+            .unwrap();
+
+        let (_, msg) = typed_receiver.recv().await.unwrap();
+        assert_eq!(msg.unwrap(), (7, "seven".to_string()));
+        let (from, msg) = typed_receiver.recv().await.unwrap();
+        assert_eq!(from, addr);
+        assert!(msg.is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use dash_common::crypto::publickey_to_base64;
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use hotstuff_rs::types::PublicKeyBytes;
+use serde::{Deserialize, Serialize};
+
+/// A token bucket: `per_sec` transactions a second on average, up to `burst` at once.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub per_sec: u32,
+    pub burst: u32,
+}
+
+/// Who may submit transactions, and how fast.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    /// Keys allowed to submit, everyone when `None`.
+    pub allowlist: Option<HashSet<PublicKeyBytes>>,
+    pub per_key: Option<RateLimit>,
+    pub per_ip: Option<RateLimit>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: RateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec as f64).min(limit.burst as f64);
+        self.refilled = now;
+    }
+
+    fn take(&mut self, limit: RateLimit) -> bool {
+        self.refill(limit);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Enforces an [`AccessPolicy`] on the transactions submitted by clients.
+pub struct AccessControl {
+    policy: AccessPolicy,
+    keys: HashMap<PublicKeyBytes, TokenBucket>,
+    ips: HashMap<IpAddr, TokenBucket>,
+}
+
+impl AccessControl {
+    pub fn new(policy: AccessPolicy) -> Self {
+        Self {
+            policy,
+            keys: Default::default(),
+            ips: Default::default(),
+        }
+    }
+
+    /// Switches to a reloaded policy. Buckets are kept, new limits apply from now on.
+    pub fn update(&mut self, policy: AccessPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn allowed(&self, key: &PublicKeyBytes) -> bool {
+        self.policy
+            .allowlist
+            .as_ref()
+            .is_none_or(|allowlist| allowlist.contains(key))
+    }
+
+    /// Takes a token of the source address, before anything is known of the request.
+    pub fn admit_ip(&mut self, ip: IpAddr) -> Result<()> {
+        if !take(&mut self.ips, ip, self.policy.per_ip) {
+            return Err(anyhow!("too many requests from {}", ip));
+        }
+        Ok(())
+    }
+
+    /// Checks the allowlist and takes a token of a verified requester.
+    pub fn admit_key(&mut self, key: PublicKeyBytes) -> Result<()> {
+        if !self.allowed(&key) {
+            return Err(anyhow!("{} is not allowed", publickey_to_base64(key)));
+        }
+        if !take(&mut self.keys, key, self.policy.per_key) {
+            return Err(anyhow!(
+                "too many transactions from {}",
+                publickey_to_base64(key)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Forgets buckets that have filled up again, they are as good as new.
+    pub fn expire(&mut self) {
+        forget_full(&mut self.keys, self.policy.per_key);
+        forget_full(&mut self.ips, self.policy.per_ip);
+    }
+}
+
+fn take<K: Eq + Hash>(
+    buckets: &mut HashMap<K, TokenBucket>,
+    key: K,
+    limit: Option<RateLimit>,
+) -> bool {
+    let Some(limit) = limit else {
+        return true;
+    };
+    buckets
+        .entry(key)
+        .or_insert_with(|| TokenBucket::full(limit))
+        .take(limit)
+}
+
+fn forget_full<K>(buckets: &mut HashMap<K, TokenBucket>, limit: Option<RateLimit>) {
+    let Some(limit) = limit else {
+        buckets.clear();
+        return;
+    };
+    buckets.retain(|_, bucket| {
+        bucket.refill(limit);
+        bucket.tokens < limit.burst as f64
+    });
+}
+
+#[cfg(test)]
+mod access_tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket_test() {
+        let (alice, bob) = ([1; 32], [2; 32]);
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut access = AccessControl::new(AccessPolicy {
+            allowlist: Some(HashSet::from([alice])),
+            per_key: Some(RateLimit {
+                per_sec: 10,
+                burst: 2,
+            }),
+            per_ip: None,
+        });
+        assert!(access.admit_key(bob).is_err());
+        assert!(access.admit_key(alice).is_ok());
+        assert!(access.admit_key(alice).is_ok());
+        assert!(access.admit_key(alice).is_err());
+        assert!(access.admit_ip(ip).is_ok());
+
+        // A token comes back every 100 ms.
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(access.admit_key(alice).is_ok());
+        assert!(access.admit_key(alice).is_err());
+
+        // Reloading lifts the allowlist and limits the source address instead.
+        access.update(AccessPolicy {
+            allowlist: None,
+            per_key: None,
+            per_ip: Some(RateLimit {
+                per_sec: 1,
+                burst: 1,
+            }),
+        });
+        assert!(access.admit_key(bob).is_ok());
+        assert!(access.admit_key(alice).is_ok());
+        assert!(access.admit_ip(ip).is_ok());
+        assert!(access.admit_ip(ip).is_err());
+        access.expire();
+        assert!(access.keys.is_empty());
+        assert_eq!(access.ips.len(), 1);
+    }
+}
@@ -0,0 +1,407 @@
+use dash_common::crypto::{self, publickey_to_base64};
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use hotstuff_rs::types::{DalekKeypair, PublicKeyBytes, SignatureBytes, ValidatorSetUpdates};
+use log::{info, trace, warn};
+
+/// Announcements of nodes that are not validators yet are kept until they join, up to this
+/// many. The one heard from the longest ago makes room for a new one.
+const MAX_CANDIDATES: usize = 1024;
+/// Candidates and observers are forgotten when they stop announcing themselves for this
+/// long, three announcement periods.
+const ANNOUNCEMENT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// A node's claim of the address it accepts peer connections on, signed with its key. The
+/// timestamp orders the claims of a node, so that an old one cannot be replayed.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct Announcement {
+    pub public_key: PublicKeyBytes,
+    // `SocketAddr` has no borsh encoding.
+    addr: String,
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+    signature: SignatureBytes,
+}
+
+impl Announcement {
+    pub fn new(keypair: &DalekKeypair, addr: SocketAddr) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        Self::signed(keypair, addr, timestamp)
+    }
+
+    fn signed(keypair: &DalekKeypair, addr: SocketAddr, timestamp: u64) -> Self {
+        let public_key = keypair.public.to_bytes();
+        let addr = addr.to_string();
+        let signature = crypto::sign(keypair, &Self::signed_bytes(&public_key, &addr, timestamp));
+        Self {
+            public_key,
+            addr,
+            timestamp,
+            signature,
+        }
+    }
+
+    /// The announced address, `None` if the signature or the address is invalid.
+    pub fn verify(&self) -> Option<SocketAddr> {
+        let bytes = Self::signed_bytes(&self.public_key, &self.addr, self.timestamp);
+        if !crypto::verify(&self.public_key, &bytes, &self.signature) {
+            return None;
+        }
+        self.addr.parse().ok()
+    }
+
+    fn signed_bytes(public_key: &PublicKeyBytes, addr: &str, timestamp: u64) -> Vec<u8> {
+        (public_key, addr, timestamp).try_to_vec().unwrap()
+    }
+}
+
+/// Where a node was last announced to be.
+struct Known {
+    addr: SocketAddr,
+    /// Timestamp of the announcement, only newer ones are taken.
+    timestamp: u64,
+    seen: Instant,
+}
+
+impl Known {
+    fn new(addr: SocketAddr, timestamp: u64) -> Self {
+        Self {
+            addr,
+            timestamp,
+            seen: Instant::now(),
+        }
+    }
+
+    /// Takes a newer announcement of the node. Returns the previous address when it moved,
+    /// `None` when it did not or the announcement is stale.
+    fn update(&mut self, announcement: &Announcement, addr: SocketAddr) -> Option<SocketAddr> {
+        if announcement.timestamp <= self.timestamp {
+            trace!(
+                "stale announcement from {}, ignored",
+                publickey_to_base64(announcement.public_key)
+            );
+            return None;
+        }
+        self.timestamp = announcement.timestamp;
+        self.seen = Instant::now();
+        (self.addr != addr).then(|| std::mem::replace(&mut self.addr, addr))
+    }
+}
+
+/// Network addresses of the validators. Starts from the genesis file and follows the
+/// validator set: joining validators are learned from their announcements, leaving ones are
+/// forgotten. Observers, nodes following the chain without voting, are known as long as
+/// they keep announcing themselves, if their key is allowed to observe.
+#[derive(Default)]
+pub struct AddressBook {
+    /// The current validators, whether their address is known or not.
+    validators: HashSet<PublicKeyBytes>,
+    addresses: HashMap<PublicKeyBytes, Known>,
+    candidates: HashMap<PublicKeyBytes, Known>,
+    /// Keys allowed to observe. Anyone may announce any address, proposals are only streamed
+    /// to the operators' own observers.
+    allowed_observers: HashSet<PublicKeyBytes>,
+    observers: HashMap<PublicKeyBytes, Known>,
+}
+
+impl AddressBook {
+    pub fn new(
+        addresses: HashMap<PublicKeyBytes, SocketAddr>,
+        allowed_observers: HashSet<PublicKeyBytes>,
+    ) -> Self {
+        Self {
+            validators: addresses.keys().copied().collect(),
+            addresses: addresses
+                .into_iter()
+                .map(|(key, addr)| (key, Known::new(addr, 0)))
+                .collect(),
+            candidates: Default::default(),
+            allowed_observers,
+            observers: Default::default(),
+        }
+    }
+
+    /// Address of a validator or an observer.
+    pub fn get(&self, key: &PublicKeyBytes) -> Option<SocketAddr> {
+        self.addresses
+            .get(key)
+            .or_else(|| self.observers.get(key))
+            .map(|known| known.addr)
+    }
+
+    /// The validators and their addresses.
+    pub fn iter(&self) -> impl Iterator<Item = (&PublicKeyBytes, &SocketAddr)> {
+        self.addresses.iter().map(|(key, known)| (key, &known.addr))
+    }
+
+    pub fn observers(&self) -> impl Iterator<Item = &PublicKeyBytes> {
+        self.observers.keys()
+    }
+
+    /// Records the address of a verified announcement newer than the last one of the node.
+    /// Returns the previous address of the validator when it moved, its connection is stale.
+    pub fn announce(&mut self, announcement: &Announcement) -> Option<SocketAddr> {
+        let key = announcement.public_key;
+        let Some(addr) = announcement.verify() else {
+            warn!(
+                "Invalid announcement from {}, ignored",
+                publickey_to_base64(key)
+            );
+            return None;
+        };
+        if self.validators.contains(&key) {
+            let Some(known) = self.addresses.get_mut(&key) else {
+                info!("validator {} found at {}", publickey_to_base64(key), addr);
+                self.addresses
+                    .insert(key, Known::new(addr, announcement.timestamp));
+                return None;
+            };
+            let moved = known.update(announcement, addr);
+            if moved.is_some() {
+                info!("validator {} moved to {}", publickey_to_base64(key), addr);
+            }
+            return moved;
+        }
+        if let Some(known) = self.candidates.get_mut(&key) {
+            known.update(announcement, addr);
+            return None;
+        }
+        if self.candidates.len() >= MAX_CANDIDATES {
+            let oldest = self
+                .candidates
+                .iter()
+                .min_by_key(|(_, known)| known.seen)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.candidates.remove(&oldest);
+            }
+        }
+        self.candidates
+            .insert(key, Known::new(addr, announcement.timestamp));
+        None
+    }
+
+    /// Records the address of an allowed observer from its verified announcement. Returns its
+    /// previous address when it moved, its connection is stale.
+    pub fn observe(&mut self, announcement: &Announcement) -> Option<SocketAddr> {
+        let key = announcement.public_key;
+        if !self.allowed_observers.contains(&key) {
+            trace!(
+                "observer {} not allowed, announcement ignored",
+                publickey_to_base64(key)
+            );
+            return None;
+        }
+        let Some(addr) = announcement.verify() else {
+            warn!(
+                "Invalid announcement from {}, ignored",
+                publickey_to_base64(key)
+            );
+            return None;
+        };
+        if self.validators.contains(&key) {
+            warn!(
+                "validator {} announced itself as an observer, ignored",
+                publickey_to_base64(key)
+            );
+            return None;
+        }
+        if let Some(known) = self.observers.get_mut(&key) {
+            let moved = known.update(announcement, addr);
+            if moved.is_some() {
+                info!("observer {} moved to {}", publickey_to_base64(key), addr);
+            }
+            return moved;
+        }
+        info!(
+            "observer {} following from {}",
+            publickey_to_base64(key),
+            addr
+        );
+        self.observers
+            .insert(key, Known::new(addr, announcement.timestamp));
+        None
+    }
+
+    /// Forgets the candidates and observers that stopped announcing themselves. Returns the
+    /// addresses of the observers, their connections are to be closed.
+    pub fn expire(&mut self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let alive = |known: &Known| now.duration_since(known.seen) < ANNOUNCEMENT_TIMEOUT;
+        self.candidates.retain(|_, known| alive(known));
+        let mut expired = vec![];
+        self.observers.retain(|key, known| {
+            if !alive(known) {
+                info!("observer {} is gone", publickey_to_base64(*key));
+                expired.push(known.addr);
+            }
+            alive(known)
+        });
+        expired
+    }
+
+    /// Follows a validator set change. Returns the addresses of the removed validators, their
+    /// connections are to be closed.
+    pub fn update(&mut self, updates: &ValidatorSetUpdates) -> Vec<SocketAddr> {
+        for (key, _) in updates.inserts() {
+            self.validators.insert(*key);
+            if self.addresses.contains_key(key) {
+                continue;
+            }
+            // An observer may be voted in, it stops being one then.
+            let announced = self
+                .candidates
+                .remove(key)
+                .or_else(|| self.observers.remove(key));
+            match announced {
+                Some(known) => {
+                    info!(
+                        "validator {} joined at {}",
+                        publickey_to_base64(*key),
+                        known.addr
+                    );
+                    self.addresses.insert(*key, known);
+                }
+                None => warn!(
+                    "validator {} joined, address unknown until it announces itself",
+                    publickey_to_base64(*key)
+                ),
+            }
+        }
+        updates
+            .deletions()
+            .filter_map(|key| {
+                self.validators.remove(key);
+                self.addresses.remove(key).map(|known| known.addr)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod address_book_tests {
+    use super::*;
+    use dash_common::crypto::generate_keypair;
+
+    #[test]
+    fn validator_set_update_test() {
+        let staying = generate_keypair();
+        let leaving = generate_keypair();
+        let joining = generate_keypair();
+        let staying_addr: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let leaving_addr: SocketAddr = "127.0.0.1:8002".parse().unwrap();
+        let joining_addr: SocketAddr = "127.0.0.1:8003".parse().unwrap();
+        let mut book = AddressBook::new(
+            HashMap::from([
+                (staying.public.to_bytes(), staying_addr),
+                (leaving.public.to_bytes(), leaving_addr),
+            ]),
+            HashSet::new(),
+        );
+
+        // Forged and not yet joined announcements do not enter the book.
+        let mut forged = Announcement::signed(&joining, joining_addr, 1);
+        forged.addr = "127.0.0.1:9000".to_string();
+        assert_eq!(book.announce(&forged), None);
+        assert_eq!(
+            book.announce(&Announcement::signed(&joining, joining_addr, 1)),
+            None
+        );
+        assert_eq!(book.get(&joining.public.to_bytes()), None);
+
+        let mut updates = ValidatorSetUpdates::new();
+        updates.insert(joining.public.to_bytes(), 1);
+        updates.delete(leaving.public.to_bytes());
+        assert_eq!(book.update(&updates), vec![leaving_addr]);
+        assert_eq!(book.get(&joining.public.to_bytes()), Some(joining_addr));
+        assert_eq!(book.get(&leaving.public.to_bytes()), None);
+
+        // Only announcements newer than the last one move a validator.
+        let moved: SocketAddr = "127.0.0.1:8004".parse().unwrap();
+        let old = Announcement::signed(&staying, staying_addr, 1);
+        assert_eq!(
+            book.announce(&Announcement::signed(&staying, moved, 2)),
+            Some(staying_addr)
+        );
+        assert_eq!(book.announce(&old), None);
+        assert_eq!(book.get(&staying.public.to_bytes()), Some(moved));
+
+        // A validator that joins before announcing itself is found once it does.
+        let late = generate_keypair();
+        let late_addr: SocketAddr = "127.0.0.1:8006".parse().unwrap();
+        let mut updates = ValidatorSetUpdates::new();
+        updates.insert(late.public.to_bytes(), 1);
+        assert!(book.update(&updates).is_empty());
+        assert_eq!(book.get(&late.public.to_bytes()), None);
+        assert_eq!(
+            book.announce(&Announcement::signed(&late, late_addr, 1)),
+            None
+        );
+        assert_eq!(book.get(&late.public.to_bytes()), Some(late_addr));
+    }
+
+    #[test]
+    fn observer_test() {
+        let validator = generate_keypair();
+        let observer = generate_keypair();
+        let unlisted = generate_keypair();
+        let validator_addr: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let observer_addr: SocketAddr = "127.0.0.1:8002".parse().unwrap();
+        let mut book = AddressBook::new(
+            HashMap::from([(validator.public.to_bytes(), validator_addr)]),
+            HashSet::from([validator.public.to_bytes(), observer.public.to_bytes()]),
+        );
+
+        // Allowed observers are reachable without being validators.
+        assert_eq!(
+            book.observe(&Announcement::new(&observer, observer_addr)),
+            None
+        );
+        assert_eq!(book.get(&observer.public.to_bytes()), Some(observer_addr));
+        assert!(book
+            .iter()
+            .all(|(key, _)| *key != observer.public.to_bytes()));
+
+        // An unlisted key is not served, whatever address it names.
+        assert_eq!(
+            book.observe(&Announcement::new(&unlisted, observer_addr)),
+            None
+        );
+        assert_eq!(book.get(&unlisted.public.to_bytes()), None);
+
+        // Validators cannot pose as one.
+        assert_eq!(
+            book.observe(&Announcement::new(&validator, observer_addr)),
+            None
+        );
+        assert_eq!(book.get(&validator.public.to_bytes()), Some(validator_addr));
+        assert_eq!(
+            book.observers().collect::<Vec<_>>(),
+            vec![&observer.public.to_bytes()]
+        );
+        assert!(book.expire().is_empty());
+    }
+
+    #[test]
+    fn candidate_eviction_test() {
+        let mut book = AddressBook::default();
+        let addr: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let first = generate_keypair();
+        book.announce(&Announcement::new(&first, addr));
+        for _ in 0..MAX_CANDIDATES {
+            book.announce(&Announcement::new(&generate_keypair(), addr));
+        }
+        // Junk fills the candidates up, a joiner still gets in and the oldest one goes.
+        let joining = generate_keypair();
+        book.announce(&Announcement::new(&joining, addr));
+        assert_eq!(book.candidates.len(), MAX_CANDIDATES);
+        assert!(book.candidates.contains_key(&joining.public.to_bytes()));
+        assert!(!book.candidates.contains_key(&first.public.to_bytes()));
+    }
+}
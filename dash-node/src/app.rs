@@ -1,25 +1,44 @@
 use crate::kv_store::KVStoreImpl;
-use dash_common::{NewTransactionRequest, TransactionHash};
+use dash_common::{
+    governance::{GovernanceTransaction, GOVERNANCE_NONCE_KEY},
+    proof::merkle_root,
+    NewTransactionRequest, TransactionData, TransactionHash,
+};
 
 use std::collections::{HashSet, VecDeque};
 
+use anyhow::{anyhow, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
 use hotstuff_rs::app::{
     App, ProduceBlockRequest, ProduceBlockResponse, ValidateBlockRequest, ValidateBlockResponse,
 };
-use log::trace;
+use hotstuff_rs::state::AppBlockTreeView;
+use hotstuff_rs::types::{AppStateUpdates, ChainID, CryptoHash, ValidatorSetUpdates};
+use log::{trace, warn};
 use tokio::sync::mpsc::Receiver;
+use tokio_util::sync::CancellationToken;
 
 pub struct AppImpl {
+    chain_id: ChainID,
     block_rx: Receiver<NewTransactionRequest>,
+    shutdown: CancellationToken,
     trans_cache: VecDeque<NewTransactionRequest>,
     committed_block: HashSet<TransactionHash>,
     highest_committed_height: u64,
 }
 
 impl AppImpl {
-    pub fn new(block_rx: Receiver<NewTransactionRequest>) -> Self {
+    /// Block production waits for transactions until `shutdown` is cancelled, then proposes
+    /// the queued ones and empty blocks. The consensus thread can only be stopped once it is.
+    pub fn new(
+        chain_id: ChainID,
+        block_rx: Receiver<NewTransactionRequest>,
+        shutdown: CancellationToken,
+    ) -> Self {
         Self {
+            chain_id,
             block_rx,
+            shutdown,
             trans_cache: Default::default(),
             committed_block: Default::default(),
             highest_committed_height: 0,
@@ -28,8 +47,8 @@ impl AppImpl {
 }
 
 impl App<KVStoreImpl> for AppImpl {
-    fn chain_id(&self) -> hotstuff_rs::types::ChainID {
-        1
+    fn chain_id(&self) -> ChainID {
+        self.chain_id
     }
 
     fn produce_block(&mut self, request: ProduceBlockRequest<KVStoreImpl>) -> ProduceBlockResponse {
@@ -64,6 +83,10 @@ impl App<KVStoreImpl> for AppImpl {
                     self.committed_block.insert(hash);
                 }
             }
+            let parent = request.parent_block();
+            // A validator set change commits once a child block is inserted.
+            let commit_pending = parent.is_some_and(|parent| has_governance(tree, &parent));
+            let mut deferred = VecDeque::new();
             while let Some(request) = self.trans_cache.pop_front() {
                 trace!("produce_block");
                 if pending_ancient.contains(&request.hash)
@@ -71,9 +94,38 @@ impl App<KVStoreImpl> for AppImpl {
                 {
                     continue;
                 }
+                let (app_state_updates, validator_set_updates) = match &request.data {
+                    TransactionData::App(_) => (None, None),
+                    TransactionData::Governance(transaction) => {
+                        match governance_updates(tree, self.chain_id, parent, transaction) {
+                            Ok((app_state_updates, validator_set_updates)) => {
+                                (Some(app_state_updates), Some(validator_set_updates))
+                            }
+                            Err(GovernanceError::Pending) => {
+                                deferred.push_back(request);
+                                continue;
+                            }
+                            Err(GovernanceError::Invalid(e)) => {
+                                warn!("Governance transaction rejected: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+                self.trans_cache.extend(deferred);
+                return ProduceBlockResponse {
+                    data_hash: merkle_root(&[request.hash]),
+                    data: vec![request.try_to_vec().unwrap()],
+                    app_state_updates,
+                    validator_set_updates,
+                };
+            }
+            self.trans_cache.extend(deferred);
+            // Empty blocks on shutdown commit the last transactions.
+            if commit_pending || self.shutdown.is_cancelled() {
                 return ProduceBlockResponse {
-                    data_hash: request.hash,
-                    data: vec![request.data],
+                    data_hash: CryptoHash::default(),
+                    data: vec![],
                     app_state_updates: None,
                     validator_set_updates: None,
                 };
@@ -85,17 +137,91 @@ impl App<KVStoreImpl> for AppImpl {
         &mut self,
         request: ValidateBlockRequest<KVStoreImpl>,
     ) -> ValidateBlockResponse {
-        if request
-            .block_tree()
-            .block_data_hash(&request.proposed_block().data_hash)
-            .is_some()
-        {
-            ValidateBlockResponse::Invalid
-        } else {
-            ValidateBlockResponse::Valid {
-                app_state_updates: None,
-                validator_set_updates: None,
+        let tree = request.block_tree();
+        let block = request.proposed_block();
+        if tree.block_data_hash(&block.data_hash).is_some() {
+            return ValidateBlockResponse::Invalid;
+        }
+        let parent = (!block.justify.is_genesis_qc()).then_some(block.justify.block);
+        let mut app_state_updates = None;
+        let mut validator_set_updates = None;
+        let mut hashes = vec![];
+        for datum in &block.data {
+            let Ok(transaction) = NewTransactionRequest::try_from_slice(datum) else {
+                return ValidateBlockResponse::Invalid;
+            };
+            if transaction.verify(self.chain_id).is_err() {
+                return ValidateBlockResponse::Invalid;
+            }
+            hashes.push(transaction.hash);
+            match transaction.data {
+                TransactionData::App(_) => (),
+                TransactionData::Governance(transaction) => {
+                    if validator_set_updates.is_some() {
+                        return ValidateBlockResponse::Invalid;
+                    }
+                    match governance_updates(tree, self.chain_id, parent, &transaction) {
+                        Ok((app_state, validator_set)) => {
+                            app_state_updates = Some(app_state);
+                            validator_set_updates = Some(validator_set);
+                        }
+                        Err(_) => return ValidateBlockResponse::Invalid,
+                    }
+                }
             }
         }
+        // Clients take inclusion proofs against the data hash.
+        if block.data_hash != merkle_root(&hashes) {
+            return ValidateBlockResponse::Invalid;
+        }
+        ValidateBlockResponse::Valid {
+            app_state_updates,
+            validator_set_updates,
+        }
+    }
+}
+
+enum GovernanceError {
+    /// The parent block changes the validator set, the signatures can only be checked against
+    /// the new one once it commits.
+    Pending,
+    Invalid(anyhow::Error),
+}
+
+/// The updates of a governance transaction in a child of `parent`.
+fn governance_updates(
+    tree: &AppBlockTreeView<KVStoreImpl>,
+    chain_id: ChainID,
+    parent: Option<CryptoHash>,
+    transaction: &GovernanceTransaction,
+) -> Result<(AppStateUpdates, ValidatorSetUpdates), GovernanceError> {
+    if let Some(parent) = parent {
+        if has_governance(tree, &parent) {
+            return Err(GovernanceError::Pending);
+        }
     }
+    let nonce = match tree.app_state(GOVERNANCE_NONCE_KEY) {
+        Some(bytes) => u64::try_from_slice(&bytes)
+            .map_err(|e| GovernanceError::Invalid(anyhow!("corrupted nonce: {}", e)))?,
+        None => 0,
+    };
+    let validator_set_updates = transaction
+        .verify(&tree.validator_set(), chain_id, nonce)
+        .map_err(GovernanceError::Invalid)?;
+    let mut app_state_updates = AppStateUpdates::new();
+    app_state_updates.insert(
+        GOVERNANCE_NONCE_KEY.to_vec(),
+        (nonce + 1).try_to_vec().unwrap(),
+    );
+    Ok((app_state_updates, validator_set_updates))
+}
+
+fn has_governance(tree: &AppBlockTreeView<KVStoreImpl>, block: &CryptoHash) -> bool {
+    tree.block_data(block)
+        .unwrap_or_default()
+        .iter()
+        .any(|datum| {
+            NewTransactionRequest::try_from_slice(datum)
+                .is_ok_and(|request| matches!(request.data, TransactionData::Governance(_)))
+        })
 }
@@ -1,168 +1,732 @@
+use crate::access::{AccessControl, AccessPolicy};
+use crate::commit_tracker::{CommitTracker, Tracked};
+use crate::feed::{fits, Feeds};
 use crate::kv_store::KVStoreImpl;
-use dash_common::{NewTransactionRequest, TransactionHash, TransactionReceipt, TransactionResult};
-use dash_network::server::Server;
+use crate::session::{Retention, Sessions};
+use dash_common::{
+    proof::{
+        BlockHeader, CommitProof, CommittedHeader, HeaderChain, HeaderRequest, InclusionProof,
+    },
+    subscription::{CommittedBlock, Subscription},
+    ClientMessage, Login, NewTransactionRequest, NodeMessage, TransactionData, TransactionHash,
+    TransactionReceipt, TransactionResult,
+};
+use dash_network::{
+    server::Server,
+    stats::Stats,
+    typed::{typed, TypedReceiver, TypedSender},
+    Options,
+};
 
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::thread;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
+use anyhow::Result;
 use borsh::{BorshDeserialize, BorshSerialize};
-use bytes::Bytes;
-use hotstuff_rs::{replica::Replica, types::PublicKeyBytes};
-use log::{error, trace};
+use hotstuff_rs::{
+    pacemaker::{DefaultPacemaker, Pacemaker},
+    replica::Replica,
+    state::{BlockTreeSnapshot, KVGet},
+    types::{BlockHeight, ChainID, CryptoHash, DalekKeypair, PublicKeyBytes, QuorumCertificate},
+};
+use log::{debug, error, info, trace, warn};
 use tokio::{
     runtime::Runtime,
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Mutex,
+        mpsc::{channel, error::TrySendError, Receiver, Sender},
+        watch,
     },
+    time,
 };
+use tokio_util::sync::CancellationToken;
+
+/// A committed transaction, with the proof of its commit if there is one.
+type Commit = (TransactionHash, Option<Box<CommitProof>>);
+
+/// Requests about the committed chain, served by the commit checker.
+enum ChainRequest {
+    Subscribe(Subscription),
+    Headers(HeaderRequest),
+}
+
+/// Headers sent in answer to a request at most.
+const MAX_HEADERS: u32 = 100;
+/// Period of dropping expired receipts and sessions.
+const SESSION_EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
 
-pub struct ClientActor();
+pub struct ClientActorConfig {
+    pub chain_id: ChainID,
+    /// Keypair of this node, receipts are signed with it.
+    pub keypair: DalekKeypair,
+    pub listen_addr: SocketAddr,
+    pub options: Options,
+    /// How long pending transactions are waited for on shutdown.
+    pub drain_timeout: Duration,
+    /// Receipts kept for clients while they are disconnected.
+    pub retention: Retention,
+    /// How long a submitted transaction is tracked before it is given up.
+    pub pending_timeout: Duration,
+    /// Transactions a client may have pending at once, more are answered busy.
+    pub max_in_flight: usize,
+    /// How long busy clients are told to wait before submitting again.
+    pub retry_after: Duration,
+    pub access: AccessPolicy,
+    /// Source address of the dashboard relays, not limited per IP here. The gateway limits
+    /// each dashboard by its own address instead.
+    pub relay_ip: Option<IpAddr>,
+    /// Transactions are turned down, an observer never proposes them.
+    pub observer: bool,
+}
+
+pub struct ClientActor {
+    listen_addr: SocketAddr,
+    access_sender: watch::Sender<AccessPolicy>,
+    shutdown: CancellationToken,
+    actor: JoinHandle<usize>,
+    checker: JoinHandle<()>,
+}
 
 impl ClientActor {
     pub fn spawn(
-        pubkey: PublicKeyBytes,
-        listen_addr: SocketAddr,
+        config: ClientActorConfig,
         block_sender: Sender<NewTransactionRequest>,
         replica: Arc<Replica<KVStoreImpl>>,
         rt: Arc<Runtime>,
-    ) {
+    ) -> Result<Self> {
+        let (server_channel, server_handle) =
+            rt.block_on(Server::spawn(config.listen_addr, config.options))?;
+        let listen_addr = server_handle.local_addr().unwrap();
+        info!("listening for clients on {}", listen_addr);
         let (sender, receiver) = channel(1000);
-        let map: Arc<Mutex<HashMap<TransactionHash, PublicKeyBytes>>> = Default::default();
-        CommitChecker::spawn(replica, sender, map.clone());
-        thread::spawn(move || {
+        let (chain_sender, chain_receiver) = channel(100);
+        let stats = server_handle.stats().clone();
+        let (net_sender, net_receiver) = typed(server_channel);
+        let checker = CommitChecker::spawn(
+            replica,
+            sender,
+            chain_receiver,
+            (
+                net_sender.clone(),
+                config.options.frame_limits.max_send_frame_length,
+            ),
+            stats.clone(),
+        );
+        let shutdown = CancellationToken::new();
+        let (access_sender, access_receiver) = watch::channel(config.access.clone());
+        let drain_timeout = config.drain_timeout;
+        let mut actor = Actor::new(
+            config,
+            (net_sender, net_receiver),
+            stats,
+            block_sender,
+            (receiver, chain_sender, access_receiver),
+            shutdown.clone(),
+        );
+        let actor = thread::spawn(move || {
             rt.block_on(async {
-                Actor::spawn(listen_addr, block_sender, receiver, pubkey, map);
-                loop {
-                    tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
-                }
-            });
+                actor.run().await;
+                let undelivered = actor.drain(drain_timeout).await;
+                server_handle.shutdown().await;
+                undelivered
+            })
         });
+        Ok(Self {
+            listen_addr,
+            access_sender,
+            shutdown,
+            actor,
+            checker,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    /// Applies a reloaded access policy to the transactions submitted from now on.
+    pub fn update_access(&self, policy: AccessPolicy) {
+        self.access_sender.send_replace(policy);
+    }
+
+    /// Stops accepting transactions, the pending ones are still followed until shutdown.
+    pub fn stop_accepting(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Stops accepting transactions, then waits up to the drain timeout for the pending ones
+    /// to commit so their receipts are delivered. Returns the number of transactions left
+    /// without a receipt.
+    pub fn shutdown(self) -> usize {
+        self.shutdown.cancel();
+        let undelivered = self.actor.join().unwrap();
+        self.checker.join().unwrap();
+        info!("client service shut down");
+        undelivered
     }
 }
 
 struct Actor {
-    #[allow(unused)]
-    listen_addr: SocketAddr,
+    chain_id: ChainID,
     block_sender: Sender<NewTransactionRequest>,
-    #[allow(unused)]
-    net_sender: Sender<(SocketAddr, Bytes)>,
-    net_receiver: Receiver<(SocketAddr, Bytes)>,
-    requesters_addr_map: HashMap<PublicKeyBytes, SocketAddr>,
-    block_requester_map: Arc<Mutex<HashMap<TransactionHash, PublicKeyBytes>>>,
-    committed_receiver: Receiver<(PublicKeyBytes, TransactionHash)>,
-    pubkey: PublicKeyBytes,
+    net_sender: TypedSender<NodeMessage>,
+    net_receiver: TypedReceiver<ClientMessage>,
+    /// Traffic statistics of the client server, a client is connected as long as it has an
+    /// entry.
+    stats: Stats,
+    sessions: Sessions,
+    tracker: CommitTracker,
+    committed_receiver: Receiver<Commit>,
+    retry_after: Duration,
+    observer: bool,
+    access: AccessControl,
+    relay_ip: Option<IpAddr>,
+    access_receiver: watch::Receiver<AccessPolicy>,
+    /// Subscriptions are served by the commit checker.
+    chain_sender: Sender<(SocketAddr, ChainRequest)>,
+    keypair: DalekKeypair,
+    shutdown: CancellationToken,
 }
 
 impl Actor {
-    fn spawn(
-        listen_addr: SocketAddr,
+    fn new(
+        config: ClientActorConfig,
+        (net_sender, net_receiver): (TypedSender<NodeMessage>, TypedReceiver<ClientMessage>),
+        stats: Stats,
         block_sender: Sender<NewTransactionRequest>,
-        committed_receiver: Receiver<(PublicKeyBytes, TransactionHash)>,
-        pubkey: PublicKeyBytes,
-        block_requester_map: Arc<Mutex<HashMap<TransactionHash, PublicKeyBytes>>>,
-    ) {
-        tokio::spawn(async move {
-            let (net_sender, net_receiver) = Server::spawn(listen_addr);
-            Self {
-                listen_addr,
-                block_sender,
-                net_sender,
-                net_receiver,
-                requesters_addr_map: Default::default(),
-                block_requester_map,
-                committed_receiver,
-                pubkey,
-            }
-            .run()
-            .await
-        });
+        (committed_receiver, chain_sender, access_receiver): (
+            Receiver<Commit>,
+            Sender<(SocketAddr, ChainRequest)>,
+            watch::Receiver<AccessPolicy>,
+        ),
+        shutdown: CancellationToken,
+    ) -> Self {
+        Self {
+            chain_id: config.chain_id,
+            block_sender,
+            net_sender,
+            net_receiver,
+            stats,
+            sessions: Sessions::new(config.retention),
+            tracker: CommitTracker::new(config.pending_timeout, config.max_in_flight),
+            committed_receiver,
+            retry_after: config.retry_after,
+            observer: config.observer,
+            access: AccessControl::new(config.access),
+            relay_ip: config.relay_ip,
+            access_receiver,
+            chain_sender,
+            keypair: config.keypair,
+            shutdown,
+        }
     }
 
+    /// Serves clients until shutdown.
     async fn run(&mut self) {
+        let mut expire = time::interval(SESSION_EXPIRE_INTERVAL);
         loop {
             tokio::select! {
-                Some((addr, msg_bytes)) = self.net_receiver.recv() => {
-                    let mut msg_bytes = msg_bytes.as_ref();
-                    if let Ok(request) = NewTransactionRequest::deserialize(&mut msg_bytes) {
-                        self.requesters_addr_map.insert(request.requester, addr);
-                        self.block_requester_map.lock().await.insert(request.hash, request.requester);
-                        self.block_sender.send(request).await.unwrap()
+                () = self.shutdown.cancelled() => return,
+                Some((addr, message)) = self.net_receiver.recv() => {
+                    // The source address is limited before anything is verified or
+                    // answered, so that floods of junk cost little and get no signed
+                    // receipt.
+                    if let Err(e) = self.admit_ip(addr.ip()) {
+                        trace!("Dropped request from {}: {}", addr, e);
+                        continue;
                     }
+                    match message {
+                        Ok(ClientMessage::Login(login)) => self.login(addr, login).await,
+                        Ok(ClientMessage::Transaction(request)) => {
+                            self.accept(addr, request).await
+                        }
+                        Ok(ClientMessage::Subscribe(subscription)) => {
+                            self.query(addr, ChainRequest::Subscribe(subscription))
+                        }
+                        Ok(ClientMessage::Headers(request)) => {
+                            self.query(addr, ChainRequest::Headers(request))
+                        }
+                        Err(e) => warn!("Malformed request from {}: {}", addr, e),
+                    }
+                }
+                Some((hash, proof)) = self.committed_receiver.recv() => {
+                    self.committed(hash, proof).await
+                }
+                Ok(()) = self.access_receiver.changed() => {
+                    info!("client access policy reloaded");
+                    let policy = self.access_receiver.borrow_and_update().clone();
+                    self.access.update(policy);
+                }
+                _ = expire.tick() => {
+                    let stats = &self.stats;
+                    self.sessions.expire(|addr| is_connected(stats, addr));
+                    self.tracker.expire();
+                    self.access.expire();
+                }
+            }
+        }
+    }
+
+    fn query(&self, addr: SocketAddr, request: ChainRequest) {
+        if self.chain_sender.try_send((addr, request)).is_err() {
+            warn!("Too many chain requests at once, {} turned down", addr);
+        }
+    }
+
+    /// Binds the client's session to `addr` and redelivers what it missed.
+    async fn login(&mut self, addr: SocketAddr, login: Login) {
+        if let Err(e) = login.verify(self.chain_id) {
+            warn!("Rejected login from {}: {}", addr, e);
+            return;
+        }
+        if !self.access.allowed(&login.public_key) {
+            warn!("Rejected login from {}: not allowed", addr);
+            return;
+        }
+        for receipt in self.sessions.bind(login.public_key, addr) {
+            self.deliver(addr, &receipt).await;
+        }
+    }
+
+    async fn accept(&mut self, addr: SocketAddr, request: NewTransactionRequest) {
+        if self.observer {
+            let reason = "observer node, submit to a validator".to_string();
+            self.send_rejection(addr, &request, reason).await;
+            return;
+        }
+        let admitted = request
+            .verify(self.chain_id)
+            .and_then(|()| self.access.admit_key(request.requester));
+        if let Err(e) = admitted {
+            warn!("Rejected transaction from {}: {}", addr, e);
+            self.send_rejection(addr, &request, e.to_string()).await;
+            return;
+        }
+        // Only a fresh login binds a session, a signed transaction can be replayed by
+        // anyone. Its receipt goes back where it came from when there is no session.
+        match self.tracker.track(request.hash, request.requester, addr) {
+            // Never wait for the app, a full queue must not hold back the other clients.
+            Tracked::New => match self.block_sender.try_send(request) {
+                Ok(()) => (),
+                Err(TrySendError::Full(request) | TrySendError::Closed(request)) => {
+                    trace!("block queue full, transaction turned down");
+                    self.tracker.forget(request.hash);
+                    self.send_busy(addr, &request).await;
+                }
+            },
+            Tracked::Pending => trace!("transaction already pending"),
+            Tracked::Committed(proof) => {
+                self.send_receipt((request.requester, addr), request.hash, proof)
+                    .await
+            }
+            Tracked::Busy => {
+                trace!("too many transactions in flight, transaction turned down");
+                self.send_busy(addr, &request).await;
+            }
+        }
+    }
+
+    fn admit_ip(&mut self, ip: IpAddr) -> Result<()> {
+        if self.relay_ip == Some(ip) {
+            return Ok(());
+        }
+        self.access.admit_ip(ip)
+    }
+
+    async fn committed(&mut self, hash: TransactionHash, proof: Option<Box<CommitProof>>) {
+        for requester in self.tracker.commit(hash, proof.clone()) {
+            self.send_receipt(requester, hash, proof.clone()).await;
+        }
+    }
+
+    /// Keeps delivering receipts, without accepting new transactions, until every pending
+    /// transaction is committed or `timeout` elapses. Returns the number of transactions
+    /// still pending or whose receipt is waiting for a disconnected client.
+    async fn drain(&mut self, timeout: Duration) -> usize {
+        let pending = self.tracker.pending();
+        info!("draining receipts of {} pending transactions", pending);
+        let deadline = time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut check = time::interval(Duration::from_millis(100));
+        loop {
+            tokio::select! {
+                Some((hash, proof)) = self.committed_receiver.recv() => {
+                    self.committed(hash, proof).await
                 }
-                Some((pubkey, hash)) = self.committed_receiver.recv() => {
-                    if let Some(addr) = self.requesters_addr_map.get(&pubkey) {
-                        trace!("send recept to {}", addr);
-                        self.net_sender
-                            .send((
-                                *addr,
-                                TransactionReceipt {
-                                    requester: pubkey,
-                                    receiptor: self.pubkey,
-                                    hash,
-                                    result: TransactionResult::Commited,
-                                }
-                                .try_to_vec()
-                                .unwrap()
-                                .into(),
-                            ))
-                            .await
-                            .unwrap();
-                    } else {
-                        error!("Unknown requester!");
+                _ = check.tick() => {
+                    if self.tracker.pending() == 0 {
+                        break;
                     }
                 }
+                () = &mut deadline => break,
+            }
+        }
+        while let Ok((hash, proof)) = self.committed_receiver.try_recv() {
+            self.committed(hash, proof).await;
+        }
+        self.tracker.pending() + self.sessions.queued()
+    }
+
+    /// Delivers the receipt of a committed transaction to the session of its requester, to
+    /// where it was submitted from if the requester has no session connected, or queues it.
+    async fn send_receipt(
+        &mut self,
+        (pubkey, submitted_from): (PublicKeyBytes, SocketAddr),
+        hash: TransactionHash,
+        proof: Option<Box<CommitProof>>,
+    ) {
+        let mut receipt = TransactionReceipt::new(
+            &self.keypair,
+            self.chain_id,
+            pubkey,
+            hash,
+            TransactionResult::Commited,
+        );
+        receipt.proof = proof;
+        let session = self
+            .sessions
+            .addr(&pubkey)
+            .filter(|addr| is_connected(&self.stats, *addr));
+        match session {
+            Some(addr) => self.deliver(addr, &receipt).await,
+            None if is_connected(&self.stats, submitted_from) => {
+                self.deliver(submitted_from, &receipt).await
             }
+            None => {
+                trace!("requester offline, queue the receipt");
+                self.sessions.queue(receipt);
+            }
+        }
+    }
+
+    async fn deliver(&mut self, addr: SocketAddr, receipt: &TransactionReceipt) {
+        trace!("send recept to {}", addr);
+        let message = NodeMessage::Receipt(receipt.clone());
+        if let Err(e) = self.net_sender.send(addr, &message).await {
+            error!("Send receipt to {} failed: {}", addr, e);
         }
     }
+
+    async fn send_rejection(
+        &mut self,
+        addr: SocketAddr,
+        request: &NewTransactionRequest,
+        reason: String,
+    ) {
+        self.reply(addr, request, TransactionResult::Unaccepted { reason })
+            .await;
+    }
+
+    async fn send_busy(&mut self, addr: SocketAddr, request: &NewTransactionRequest) {
+        let retry_after_ms = self.retry_after.as_millis() as u64;
+        self.reply(addr, request, TransactionResult::Busy { retry_after_ms })
+            .await;
+    }
+
+    /// Answers a transaction that is not tracked, straight to where it came from.
+    async fn reply(
+        &mut self,
+        addr: SocketAddr,
+        request: &NewTransactionRequest,
+        result: TransactionResult,
+    ) {
+        let receipt = TransactionReceipt::new(
+            &self.keypair,
+            self.chain_id,
+            request.requester,
+            request.hash,
+            result,
+        );
+        self.deliver(addr, &receipt).await;
+    }
+}
+
+fn is_connected(stats: &Stats, addr: SocketAddr) -> bool {
+    stats.peer(&addr).is_some_and(|peer| peer.connected)
 }
+
+/// Hands the data hashes of committed blocks over to the actor, feeds the committed chain
+/// to subscribers and answers header requests.
 struct CommitChecker {
     replica: Arc<Replica<KVStoreImpl>>,
-    committed_sender: Sender<(PublicKeyBytes, TransactionHash)>,
-    block_sender_map: Arc<Mutex<HashMap<TransactionHash, PublicKeyBytes>>>,
+    committed_sender: Sender<Commit>,
+    chain_receiver: Receiver<(SocketAddr, ChainRequest)>,
+    net_sender: TypedSender<NodeMessage>,
+    /// Longest frame a client is sent.
+    max_frame_length: usize,
+    stats: Stats,
+    feeds: Feeds,
+    /// Height of the last committed block changing the validator set.
+    last_validator_change: Option<BlockHeight>,
 }
 
 impl CommitChecker {
     fn spawn(
         replica: Arc<Replica<KVStoreImpl>>,
-        committed_sender: Sender<(PublicKeyBytes, TransactionHash)>,
-        block_sender_map: Arc<Mutex<HashMap<TransactionHash, PublicKeyBytes>>>,
-    ) {
+        committed_sender: Sender<Commit>,
+        chain_receiver: Receiver<(SocketAddr, ChainRequest)>,
+        (net_sender, max_frame_length): (TypedSender<NodeMessage>, usize),
+        stats: Stats,
+    ) -> JoinHandle<()> {
         thread::spawn(move || {
-            let mut checker = Self {
+            Self {
                 replica,
                 committed_sender,
-                block_sender_map,
-            };
-            loop {
-                checker.run();
+                chain_receiver,
+                net_sender,
+                max_frame_length,
+                stats,
+                feeds: Feeds::new(max_frame_length),
+                last_validator_change: None,
             }
-        });
+            .run()
+        })
     }
 
+    /// Polls the committed blocks until the actor is gone.
     fn run(&mut self) {
-        let mut receipted_height = 0;
-        loop {
+        // The first block is at height 0.
+        let mut next_height = 0;
+        while !self.committed_sender.is_closed() {
             let snapshot = self.replica.block_tree_camera().snapshot();
-            trace!("receipted height {}", receipted_height);
+            while let Ok((addr, request)) = self.chain_receiver.try_recv() {
+                match request {
+                    ChainRequest::Subscribe(subscription) => {
+                        self.feeds.subscribe(addr, subscription, next_height);
+                    }
+                    ChainRequest::Headers(request) => {
+                        let Some(message) =
+                            header_chain(&snapshot, &request, self.max_frame_length)
+                        else {
+                            debug!(
+                                "no headers from height {} for {}",
+                                request.start_height, addr
+                            );
+                            continue;
+                        };
+                        if !fits(&message, self.max_frame_length) {
+                            warn!(
+                                "Block {} is too large to send, headers for {} dropped",
+                                request.start_height, addr
+                            );
+                            continue;
+                        }
+                        if self.net_sender.blocking_send(addr, &message).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            trace!("next height to receipt {}", next_height);
+            let mut lagging = false;
             if let Some(hc_block) = snapshot.highest_committed_block() {
                 let highest_commited_height = snapshot.block_height(&hc_block).unwrap();
                 trace!("commited height {}", highest_commited_height);
-                for height in receipted_height + 1..=highest_commited_height {
+                for height in next_height..=highest_commited_height {
                     let block = snapshot.block_at_height(height).unwrap();
-                    let hash = snapshot.block_data_hash(&block).unwrap();
-                    let Some(pubkey) = self.block_sender_map.blocking_lock().remove(&hash) else {
-                        error!("Unknown block!");
+                    if snapshot.block_data_len(&block) == Some(0) {
+                        // Empty blocks only carry consensus progress.
                         continue;
-                    };
-                    self.committed_sender.blocking_send((pubkey, hash)).unwrap();
+                    }
+                    let requests = block_requests(&snapshot, &block);
+                    if requests
+                        .iter()
+                        .any(|request| matches!(request.data, TransactionData::Governance(_)))
+                    {
+                        self.last_validator_change = Some(height);
+                    }
+                    for committed in committed_transactions(&snapshot, &block) {
+                        if self.committed_sender.blocking_send(committed).is_err() {
+                            return;
+                        }
+                    }
+                }
+                next_height = highest_commited_height + 1;
+
+                let stats = &self.stats;
+                let last_change = self.last_validator_change;
+                let messages = self.feeds.poll(
+                    highest_commited_height,
+                    |height| committed_block(&snapshot, height, last_change),
+                    |addr| is_connected(stats, addr),
+                );
+                for (addr, message) in messages {
+                    if self.net_sender.blocking_send(addr, &message).is_err() {
+                        return;
+                    }
                 }
-                receipted_height = highest_commited_height;
+                lagging = self.feeds.lagging(highest_commited_height);
+            }
+            // Subscribers catching up are served without a pause.
+            if !lagging {
+                thread::sleep(Duration::from_millis(500));
             }
-            thread::sleep(Duration::from_millis(500));
         }
     }
 }
+
+/// The transactions of a committed block, each with a proof of its commit if the
+/// certificates it takes are still in the block tree.
+fn committed_transactions<S: KVGet>(
+    snapshot: &BlockTreeSnapshot<S>,
+    block: &CryptoHash,
+) -> Vec<Commit> {
+    let hashes: Vec<_> = block_requests(snapshot, block)
+        .iter()
+        .map(|request| request.hash)
+        .collect();
+    let header = block_header(snapshot, block);
+    let chain = commit_chain(snapshot, block, 0);
+    hashes
+        .iter()
+        .enumerate()
+        .map(|(index, hash)| {
+            let proof =
+                header
+                    .clone()
+                    .zip(chain.clone())
+                    .and_then(|(header, (descendants, qc))| {
+                        Some(Box::new(CommitProof {
+                            header,
+                            inclusion: InclusionProof::new(&hashes, index)?,
+                            descendants,
+                            qc,
+                        }))
+                    });
+            (*hash, proof)
+        })
+        .collect()
+}
+
+/// The committed headers `request` asks for, with what proves the last of them committed.
+/// Headers are left out from the end until the answer fits in `max_frame_length`, the
+/// first one is always there.
+fn header_chain<S: KVGet>(
+    snapshot: &BlockTreeSnapshot<S>,
+    request: &HeaderRequest,
+    max_frame_length: usize,
+) -> Option<NodeMessage> {
+    let highest = snapshot.block_height(&snapshot.highest_committed_block()?)?;
+    let count = request.count.clamp(1, MAX_HEADERS) as u64;
+    let end = highest.min(request.start_height.saturating_add(count - 1));
+    let mut headers = vec![];
+    let mut blocks = vec![];
+    let mut len = 0;
+    for height in request.start_height..=end {
+        let block = snapshot.block_at_height(height)?;
+        let header = CommittedHeader {
+            header: block_header(snapshot, &block)?,
+            transactions: block_requests(snapshot, &block),
+        };
+        len += header.try_to_vec().ok()?.len();
+        if len >= max_frame_length && !headers.is_empty() {
+            break;
+        }
+        headers.push(header);
+        blocks.push(block);
+    }
+    // The certificates of the last header come on top, which may take one more out.
+    loop {
+        let (descendants, qc) = commit_chain(snapshot, blocks.last()?, 0)?;
+        let message = NodeMessage::Headers(HeaderChain {
+            headers,
+            descendants,
+            qc,
+        });
+        if blocks.len() == 1 || fits(&message, max_frame_length) {
+            return Some(message);
+        }
+        let NodeMessage::Headers(chain) = message else {
+            unreachable!()
+        };
+        headers = chain.headers;
+        headers.pop();
+        blocks.pop();
+    }
+}
+
+fn block_requests<S: KVGet>(
+    snapshot: &BlockTreeSnapshot<S>,
+    block: &CryptoHash,
+) -> Vec<NewTransactionRequest> {
+    snapshot
+        .block_data(block)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|datum| NewTransactionRequest::try_from_slice(datum).ok())
+        .collect()
+}
+
+fn block_header<S: KVGet>(
+    snapshot: &BlockTreeSnapshot<S>,
+    block: &CryptoHash,
+) -> Option<BlockHeader> {
+    Some(BlockHeader {
+        height: snapshot.block_height(block)?,
+        justify: snapshot.block_justify(block)?,
+        data_hash: snapshot.block_data_hash(block)?,
+    })
+}
+
+/// Descendants of `block` and the certificate that together commit it, looking `depth`
+/// blocks down already. A commit certificate of a child will do at any depth, a generic
+/// one once two descendants are on the chain.
+fn commit_chain<S: KVGet>(
+    snapshot: &BlockTreeSnapshot<S>,
+    block: &CryptoHash,
+    depth: usize,
+) -> Option<(Vec<BlockHeader>, QuorumCertificate)> {
+    let children = snapshot.children(block).unwrap_or_default();
+    for child in &children {
+        let justify = snapshot.block_justify(child)?;
+        if justify.phase.is_commit() || (justify.phase.is_generic() && depth >= 2) {
+            return Some((vec![], justify));
+        }
+    }
+    if depth >= 2 {
+        return None;
+    }
+    children.iter().find_map(|child| {
+        let (mut descendants, qc) = commit_chain(snapshot, child, depth + 1)?;
+        descendants.insert(0, block_header(snapshot, child)?);
+        Some((descendants, qc))
+    })
+}
+
+/// The committed block at `height`. Its proposer is only told when the validator set has
+/// not changed since it was proposed, `last_validator_change` being the height of the last
+/// block that changed it.
+fn committed_block<S: KVGet>(
+    snapshot: &BlockTreeSnapshot<S>,
+    height: BlockHeight,
+    last_validator_change: Option<BlockHeight>,
+) -> Option<CommittedBlock> {
+    let hash = snapshot.block_at_height(height)?;
+    let transactions = block_requests(snapshot, &hash);
+    // The block was proposed in the view its quorum certificate was formed in, found in
+    // the justify of a child. Later phases of the same block carry later views.
+    let view = snapshot
+        .children(&hash)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|child| snapshot.block_justify(child))
+        .filter(|justify| justify.block == hash)
+        .map(|justify| justify.view)
+        .min();
+    // Blocks three below are committed, with any validator set change they carry, before a
+    // block is proposed. A change later than that may be unknown to the leader of its view.
+    let same_validators = last_validator_change.is_none_or(|change| change + 3 < height);
+    let proposer = view.filter(|_| same_validators).map(|view| {
+        // Leaders only depend on the view and the validator set, the timeouts are unused.
+        DefaultPacemaker::new(Duration::ZERO, 0, Duration::ZERO)
+            .view_leader(view, &snapshot.committed_validator_set())
+    });
+    Some(CommittedBlock {
+        height,
+        hash,
+        view,
+        proposer,
+        transactions,
+    })
+}
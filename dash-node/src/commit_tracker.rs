@@ -0,0 +1,222 @@
+use dash_common::{crypto::publickey_to_base64, proof::CommitProof, TransactionHash};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use hotstuff_rs::types::PublicKeyBytes;
+use log::warn;
+
+/// What became of a submission.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Tracked {
+    /// First submission of the transaction, it is to be handed to consensus.
+    New,
+    /// Already on its way to commit, the requester gets a receipt along with the others.
+    Pending,
+    /// Committed recently, the requester can be answered right away, with the proof of the
+    /// commit if there is one.
+    Committed(Option<Box<CommitProof>>),
+    /// The requester has too many transactions in flight already, it is not tracked.
+    Busy,
+}
+
+struct Pending {
+    /// Where each requester submitted the transaction from.
+    requesters: HashMap<PublicKeyBytes, SocketAddr>,
+    submitted: Instant,
+}
+
+/// Transactions submitted by clients and waiting to commit, with everyone interested in
+/// each of them.
+pub struct CommitTracker {
+    pending: HashMap<TransactionHash, Pending>,
+    committed: HashMap<TransactionHash, (Instant, Option<Box<CommitProof>>)>,
+    /// Pending transactions of each requester.
+    in_flight: HashMap<PublicKeyBytes, usize>,
+    /// Pending transactions are given up after this long, committed ones are remembered
+    /// as long.
+    timeout: Duration,
+    max_in_flight: usize,
+}
+
+impl CommitTracker {
+    pub fn new(timeout: Duration, max_in_flight: usize) -> Self {
+        Self {
+            pending: Default::default(),
+            committed: Default::default(),
+            in_flight: Default::default(),
+            timeout,
+            max_in_flight,
+        }
+    }
+
+    /// Records the interest of `requester` in a transaction submitted from `addr`.
+    /// Submitting a transaction again is harmless, every requester gets a single receipt.
+    pub fn track(
+        &mut self,
+        hash: TransactionHash,
+        requester: PublicKeyBytes,
+        addr: SocketAddr,
+    ) -> Tracked {
+        if let Some((_, proof)) = self.committed.get(&hash) {
+            return Tracked::Committed(proof.clone());
+        }
+        let known = self
+            .pending
+            .get(&hash)
+            .is_some_and(|pending| pending.requesters.contains_key(&requester));
+        let in_flight = self.in_flight.entry(requester).or_default();
+        if !known {
+            if *in_flight >= self.max_in_flight {
+                return Tracked::Busy;
+            }
+            *in_flight += 1;
+        }
+        match self.pending.get_mut(&hash) {
+            Some(pending) => {
+                pending.requesters.entry(requester).or_insert(addr);
+                Tracked::Pending
+            }
+            None => {
+                self.pending.insert(
+                    hash,
+                    Pending {
+                        requesters: HashMap::from([(requester, addr)]),
+                        submitted: Instant::now(),
+                    },
+                );
+                Tracked::New
+            }
+        }
+    }
+
+    /// Stops tracking a new transaction that could not be handed to consensus after all.
+    pub fn forget(&mut self, hash: TransactionHash) {
+        if let Some(pending) = self.pending.remove(&hash) {
+            self.release(pending.requesters.keys());
+        }
+    }
+
+    /// Marks a transaction committed. Returns its requesters with where they submitted it
+    /// from, none for a transaction submitted through other nodes. The proof is only kept
+    /// for transactions submitted here, so that memory follows the local load rather than
+    /// the throughput of the chain.
+    pub fn commit(
+        &mut self,
+        hash: TransactionHash,
+        proof: Option<Box<CommitProof>>,
+    ) -> Vec<(PublicKeyBytes, SocketAddr)> {
+        let Some(pending) = self.pending.remove(&hash) else {
+            self.committed.insert(hash, (Instant::now(), None));
+            return vec![];
+        };
+        self.committed.insert(hash, (Instant::now(), proof));
+        self.release(pending.requesters.keys());
+        pending.requesters.into_iter().collect()
+    }
+
+    /// Number of transactions waiting to commit.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Gives up transactions that did not commit within the timeout, and forgets commits
+    /// older than that.
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        let mut expired = vec![];
+        self.pending.retain(|hash, pending| {
+            let alive = pending.submitted.elapsed() < timeout;
+            if !alive {
+                warn!(
+                    "Transaction {} did not commit within {:?}, requested by {}",
+                    publickey_to_base64(*hash),
+                    timeout,
+                    pending
+                        .requesters
+                        .keys()
+                        .map(|key| publickey_to_base64(*key))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                expired.extend(pending.requesters.keys().copied());
+            }
+            alive
+        });
+        self.release(&expired);
+        self.committed
+            .retain(|_, (committed, _)| committed.elapsed() < timeout);
+    }
+
+    fn release<'a>(&mut self, requesters: impl IntoIterator<Item = &'a PublicKeyBytes>) {
+        for requester in requesters {
+            if let Some(in_flight) = self.in_flight.get_mut(requester) {
+                *in_flight -= 1;
+                if *in_flight == 0 {
+                    self.in_flight.remove(requester);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod commit_tracker_tests {
+    use super::*;
+    use dash_common::proof::{BlockHeader, InclusionProof};
+    use hotstuff_rs::types::QuorumCertificate;
+
+    #[test]
+    fn requesters_test() {
+        let mut tracker = CommitTracker::new(Duration::from_millis(100), 2);
+        let (alice, bob) = ([1; 32], [2; 32]);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        assert_eq!(tracker.track([1; 32], alice, addr), Tracked::New);
+        assert_eq!(tracker.track([1; 32], bob, addr), Tracked::Pending);
+        // The first submission of a requester is the one answered.
+        assert_eq!(tracker.track([1; 32], bob, other), Tracked::Pending);
+        assert_eq!(tracker.pending(), 1);
+
+        let mut requesters = tracker.commit([1; 32], None);
+        requesters.sort();
+        assert_eq!(requesters, vec![(alice, addr), (bob, addr)]);
+        assert_eq!(tracker.pending(), 0);
+        assert_eq!(
+            tracker.track([1; 32], alice, addr),
+            Tracked::Committed(None)
+        );
+        // Transactions submitted through other nodes are remembered without their proof.
+        let proof = CommitProof {
+            header: BlockHeader {
+                height: 0,
+                justify: QuorumCertificate::genesis_qc(),
+                data_hash: [3; 32],
+            },
+            inclusion: InclusionProof::new(&[[3; 32]], 0).unwrap(),
+            descendants: vec![],
+            qc: QuorumCertificate::genesis_qc(),
+        };
+        assert!(tracker.commit([3; 32], Some(Box::new(proof))).is_empty());
+        assert_eq!(tracker.track([3; 32], bob, addr), Tracked::Committed(None));
+
+        // A requester gets only so many transactions in flight, submitting one of them again
+        // does not count.
+        assert_eq!(tracker.track([4; 32], alice, addr), Tracked::New);
+        assert_eq!(tracker.track([5; 32], alice, addr), Tracked::New);
+        assert_eq!(tracker.track([5; 32], alice, addr), Tracked::Pending);
+        assert_eq!(tracker.track([6; 32], alice, addr), Tracked::Busy);
+        tracker.forget([5; 32]);
+        assert_eq!(tracker.track([6; 32], alice, addr), Tracked::New);
+        tracker.commit([4; 32], None);
+        tracker.commit([6; 32], None);
+
+        // Neither pending nor committed transactions are kept forever.
+        assert_eq!(tracker.track([2; 32], alice, addr), Tracked::New);
+        std::thread::sleep(Duration::from_millis(150));
+        tracker.expire();
+        assert_eq!(tracker.pending(), 0);
+        assert_eq!(tracker.track([1; 32], alice, addr), Tracked::New);
+    }
+}
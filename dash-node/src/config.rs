@@ -1,15 +1,18 @@
+use crate::access::{AccessPolicy, RateLimit};
+use crate::genesis::Genesis;
+use crate::session::Retention;
 use dash_common::crypto;
+use dash_network::{FrameLimits, Heartbeat, Options};
 
-use std::collections::{HashMap, HashSet};
 use std::env::current_exe;
-use std::fs::{read_dir, read_to_string, File};
-use std::io::Read;
+use std::fs::read_to_string;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
-use hotstuff_rs::types::{DalekKeypair, PublicKeyBytes};
+use hotstuff_rs::types::{DalekKeypair, Power, PublicKeyBytes};
 use log::debug;
 use serde::{Deserialize, Deserializer, Serialize};
 
@@ -17,12 +20,29 @@ use serde::{Deserialize, Deserializer, Serialize};
 pub struct Config {
     pub peer_listen_addr: SocketAddr,
     pub client_listen_addr: SocketAddr,
+    /// Dashboards are served over WebSocket here, none when unset.
+    #[serde(default)]
+    pub websocket_listen_addr: Option<SocketAddr>,
+    #[serde(default = "default_websocket_max_connections")]
+    pub websocket_max_connections: usize,
+    #[serde(default = "default_websocket_max_connections_per_ip")]
+    pub websocket_max_connections_per_ip: usize,
+    /// Follows the chain through block sync without voting or proposing. The key of an
+    /// observer is not in the validator set, transactions are submitted to validators.
+    #[serde(default)]
+    pub observer: bool,
+    /// Observer keys that proposals are streamed to, none when unset.
+    #[serde(
+        default,
+        deserialize_with = "parse_pubkeys",
+        serialize_with = "serialize_pubkeys"
+    )]
+    pub observer_allowlist: Option<Vec<PublicKeyBytes>>,
     #[serde(skip)]
     pub my_keypair: Option<DalekKeypair>,
+    /// Loaded from `genesis.yaml`.
     #[serde(skip)]
-    pub peer_addresses: HashMap<PublicKeyBytes, SocketAddr>,
-    #[serde(skip)]
-    pub validators: HashSet<PublicKeyBytes>,
+    pub genesis: Option<Genesis>,
     #[serde(
         deserialize_with = "parse_milliseconds",
         serialize_with = "serialize_milliseconds",
@@ -36,6 +56,78 @@ pub struct Config {
         rename = "sync_response_timeout_ms"
     )]
     pub sync_response_timeout: Duration,
+    #[serde(default)]
+    pub peer_transport: PeerTransport,
+    #[serde(default = "default_peer_max_frame_length")]
+    pub peer_max_frame_length: usize,
+    #[serde(default = "default_peer_max_frames_per_sec")]
+    pub peer_max_frames_per_sec: u32,
+    #[serde(default = "default_client_max_frame_length")]
+    pub client_max_frame_length: usize,
+    /// Answers to clients may be larger than their requests, headers carry whole blocks.
+    #[serde(default = "default_client_max_send_frame_length")]
+    pub client_max_send_frame_length: usize,
+    #[serde(default = "default_client_max_frames_per_sec")]
+    pub client_max_frames_per_sec: u32,
+    #[serde(default = "default_client_session_max_receipts")]
+    pub client_session_max_receipts: usize,
+    #[serde(
+        default = "default_client_session_retention",
+        deserialize_with = "parse_milliseconds",
+        serialize_with = "serialize_milliseconds",
+        rename = "client_session_retention_ms"
+    )]
+    pub client_session_retention: Duration,
+    #[serde(
+        default = "default_client_pending_timeout",
+        deserialize_with = "parse_milliseconds",
+        serialize_with = "serialize_milliseconds",
+        rename = "client_pending_timeout_ms"
+    )]
+    pub client_pending_timeout: Duration,
+    /// Client keys allowed to submit transactions, everyone when unset.
+    #[serde(
+        default,
+        deserialize_with = "parse_pubkeys",
+        serialize_with = "serialize_pubkeys"
+    )]
+    pub client_allowlist: Option<Vec<PublicKeyBytes>>,
+    #[serde(default)]
+    pub client_key_rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub client_ip_rate_limit: Option<RateLimit>,
+    #[serde(default = "default_client_max_in_flight")]
+    pub client_max_in_flight: usize,
+    #[serde(
+        default = "default_client_retry_after",
+        deserialize_with = "parse_milliseconds",
+        serialize_with = "serialize_milliseconds",
+        rename = "client_retry_after_ms"
+    )]
+    pub client_retry_after: Duration,
+    #[serde(
+        default = "default_heartbeat_interval",
+        deserialize_with = "parse_milliseconds",
+        serialize_with = "serialize_milliseconds",
+        rename = "heartbeat_interval_ms"
+    )]
+    pub heartbeat_interval: Duration,
+    #[serde(default = "default_max_missed_heartbeats")]
+    pub max_missed_heartbeats: u32,
+    #[serde(
+        default = "default_stats_log_interval",
+        deserialize_with = "parse_milliseconds",
+        serialize_with = "serialize_milliseconds",
+        rename = "stats_log_interval_ms"
+    )]
+    pub stats_log_interval: Duration,
+    #[serde(
+        default = "default_shutdown_timeout",
+        deserialize_with = "parse_milliseconds",
+        serialize_with = "serialize_milliseconds",
+        rename = "shutdown_timeout_ms"
+    )]
+    pub shutdown_timeout: Duration,
 }
 
 impl Config {
@@ -44,7 +136,7 @@ impl Config {
             return Err(anyhow!("config dir not found, or not a directory"));
         }
         let config_path = config_dir.as_ref().join("config.yaml");
-        let peers_dir = config_dir.as_ref().join("peers");
+        let genesis_path = config_dir.as_ref().join("genesis.yaml");
         let seckey_path = config_dir.as_ref().join("sec_key");
         let config_str = read_to_string(config_path).expect("Cannot read config.yaml!");
         let mut res = serde_yaml::from_str::<Config>(&config_str)?;
@@ -55,42 +147,169 @@ impl Config {
             crypto::publickey_to_base64(keypair.public.to_bytes())
         );
         res.my_keypair = Some(keypair);
-        res.load_peers(peers_dir);
+        res.genesis = Some(Genesis::from_path(genesis_path)?);
         Ok(res)
     }
 
+    pub fn peer_options(&self) -> Options {
+        Options {
+            frame_limits: FrameLimits {
+                max_frame_length: self.peer_max_frame_length,
+                max_send_frame_length: self.peer_max_frame_length,
+                max_frames_per_sec: self.peer_max_frames_per_sec,
+                ..Default::default()
+            },
+            heartbeat: self.heartbeat(),
+            network_id: self.genesis.as_ref().map(|genesis| genesis.genesis_hash),
+        }
+    }
+
+    pub fn client_options(&self) -> Options {
+        Options {
+            frame_limits: FrameLimits {
+                max_frame_length: self.client_max_frame_length,
+                max_send_frame_length: self.client_max_send_frame_length,
+                max_frames_per_sec: self.client_max_frames_per_sec,
+                ..Default::default()
+            },
+            heartbeat: self.heartbeat(),
+            network_id: None,
+        }
+    }
+
+    /// Options of the connections dashboards are relayed over, which read what the client
+    /// service sends.
+    pub fn relay_options(&self) -> Options {
+        let mut options = self.client_options();
+        options.frame_limits.max_frame_length = self.client_max_send_frame_length;
+        options.frame_limits.max_send_frame_length = self.client_max_frame_length;
+        options
+    }
+
+    /// Receipts kept for each disconnected client until it logs in again.
+    pub fn session_retention(&self) -> Retention {
+        Retention {
+            max_receipts: self.client_session_max_receipts,
+            max_age: self.client_session_retention,
+        }
+    }
+
+    /// Who may submit transactions and how fast, the part of the config reloaded on
+    /// SIGHUP.
+    pub fn access_policy(&self) -> AccessPolicy {
+        AccessPolicy {
+            allowlist: self
+                .client_allowlist
+                .as_ref()
+                .map(|keys| keys.iter().copied().collect()),
+            per_key: self.client_key_rate_limit,
+            per_ip: self.client_ip_rate_limit,
+        }
+    }
+
+    fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            interval: self.heartbeat_interval,
+            max_missed: self.max_missed_heartbeats,
+        }
+    }
+
     pub fn new() -> Result<Self> {
+        Self::from_path(Self::default_dir()?)
+    }
+
+    /// The `config` directory next to the executable.
+    pub fn default_dir() -> Result<PathBuf> {
         let current_exe = current_exe()?;
-        let config_dir = current_exe.parent().unwrap().join("config");
-        let res = Self::from_path(config_dir)?;
-        Ok(res)
+        Ok(current_exe.parent().unwrap().join("config"))
     }
+}
+
+/// Transport of consensus traffic, every node of a cluster must use the same one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerTransport {
+    #[default]
+    Tcp,
+    Quic,
+}
+
+impl FromStr for PeerTransport {
+    type Err = anyhow::Error;
 
-    fn load_peers(&mut self, peers_dir: PathBuf) {
-        let confs_entry: Result<Vec<_>, _> = read_dir(peers_dir)
-            .expect("Cannot access peers directory!")
-            .collect();
-        self.peer_addresses = confs_entry
-            .expect("Cannot access peers directory!")
-            .into_iter()
-            .filter_map(|entry| {
-                if entry.path().is_file() {
-                    let mut f = File::open(entry.path()).expect("Cannot open the peer config!");
-                    let mut buf = String::new();
-                    f.read_to_string(&mut buf)
-                        .expect("Cannot read the peer config!");
-                    let conf = serde_yaml::from_str::<PeerConfig>(&buf)
-                        .expect("Parse the peer config failed!");
-                    Some((conf.public_key, conf.host_addr))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        self.validators = self.peer_addresses.keys().copied().collect();
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "quic" => Ok(Self::Quic),
+            _ => Err(anyhow!("unknown transport {}, expect tcp or quic", s)),
+        }
     }
 }
 
+fn default_peer_max_frame_length() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_peer_max_frames_per_sec() -> u32 {
+    10_000
+}
+
+fn default_client_max_frame_length() -> usize {
+    64 * 1024
+}
+
+fn default_client_max_send_frame_length() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_client_max_frames_per_sec() -> u32 {
+    1_000
+}
+
+fn default_client_session_max_receipts() -> usize {
+    1_000
+}
+
+fn default_client_session_retention() -> Duration {
+    Duration::from_millis(60_000)
+}
+
+fn default_client_pending_timeout() -> Duration {
+    Duration::from_millis(120_000)
+}
+
+fn default_client_max_in_flight() -> usize {
+    100
+}
+
+fn default_client_retry_after() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_websocket_max_connections() -> usize {
+    256
+}
+
+fn default_websocket_max_connections_per_ip() -> usize {
+    8
+}
+
+fn default_heartbeat_interval() -> Duration {
+    Duration::from_millis(1000)
+}
+
+fn default_max_missed_heartbeats() -> u32 {
+    3
+}
+
+fn default_stats_log_interval() -> Duration {
+    Duration::from_millis(10_000)
+}
+
+fn default_shutdown_timeout() -> Duration {
+    Duration::from_millis(5000)
+}
+
 fn parse_milliseconds<'de, D>(d: D) -> Result<Duration, D::Error>
 where
     D: Deserializer<'de>,
@@ -99,11 +318,29 @@ where
     Ok(Duration::from_millis(millisecs))
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PeerConfig {
     pub host_addr: SocketAddr,
     #[serde(deserialize_with = "parse_pubkey", serialize_with = "serialize_pubkey")]
     pub public_key: PublicKeyBytes,
+    /// Voting power of the peer, 1 when omitted.
+    #[serde(default = "default_power", deserialize_with = "parse_power")]
+    pub power: Power,
+}
+
+fn default_power() -> Power {
+    1
+}
+
+fn parse_power<'de, D>(d: D) -> Result<Power, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let power: Power = Deserialize::deserialize(d)?;
+    if power == 0 {
+        return Err(serde::de::Error::custom("power must be positive"));
+    }
+    Ok(power)
 }
 
 fn parse_pubkey<'de, D>(d: D) -> Result<PublicKeyBytes, D::Error>
@@ -121,9 +358,54 @@ where
     s.serialize_str(&crypto::publickey_to_base64(*key))
 }
 
+fn parse_pubkeys<'de, D>(d: D) -> Result<Option<Vec<PublicKeyBytes>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pubkeys: Option<Vec<String>> = Deserialize::deserialize(d)?;
+    pubkeys
+        .map(|pubkeys| {
+            pubkeys
+                .iter()
+                .map(|pubkey| {
+                    crypto::publickey_from_base64(pubkey).map_err(serde::de::Error::custom)
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+fn serialize_pubkeys<S>(keys: &Option<Vec<PublicKeyBytes>>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let encoded: Option<Vec<String>> = keys.as_ref().map(|keys| {
+        keys.iter()
+            .map(|key| crypto::publickey_to_base64(*key))
+            .collect()
+    });
+    Serialize::serialize(&encoded, s)
+}
+
 fn serialize_milliseconds<S>(duration: &Duration, s: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
     s.serialize_u128(duration.as_millis())
 }
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    #[test]
+    fn peer_power_test() {
+        let peer = "host_addr: 127.0.0.1:8080\n\
+                    public_key: db3MWGjrGbXuxXyLCU02rh/MyowpwfHIh8etJF5wVmI=\n";
+        let conf = serde_yaml::from_str::<PeerConfig>(peer).unwrap();
+        assert_eq!(conf.power, 1);
+        let conf = serde_yaml::from_str::<PeerConfig>(&format!("{}power: 3\n", peer)).unwrap();
+        assert_eq!(conf.power, 3);
+        assert!(serde_yaml::from_str::<PeerConfig>(&format!("{}power: 0\n", peer)).is_err());
+    }
+}
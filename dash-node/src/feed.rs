@@ -0,0 +1,263 @@
+use dash_common::{
+    subscription::{
+        AppEvent, CommittedBlock, CommittedEvent, CommittedTransaction, Subscription, Topic,
+    },
+    NodeMessage,
+};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use borsh::BorshSerialize;
+use hotstuff_rs::types::BlockHeight;
+use log::{debug, warn};
+
+/// Subscriptions served at once, across all clients.
+const MAX_SUBSCRIPTIONS: usize = 1024;
+/// Blocks a lagging subscriber is sent per round, so that catching up one does not hold
+/// back the others.
+const CATCH_UP_BATCH: u64 = 100;
+
+struct Subscriber {
+    addr: SocketAddr,
+    topic: Topic,
+    next_height: BlockHeight,
+}
+
+/// Feeds of the committed chain to subscribed clients. Every subscriber moves along the
+/// chain at its own pace, from its start height to the newest committed block.
+pub struct Feeds {
+    subscribers: Vec<Subscriber>,
+    /// Longest frame a client is sent, larger notifications are skipped.
+    max_frame_length: usize,
+}
+
+impl Feeds {
+    pub fn new(max_frame_length: usize) -> Self {
+        Self {
+            subscribers: vec![],
+            max_frame_length,
+        }
+    }
+
+    /// Adds a subscription, `next_height` is the height of the next block to commit.
+    /// Returns false when the node serves too many subscriptions already.
+    pub fn subscribe(
+        &mut self,
+        addr: SocketAddr,
+        subscription: Subscription,
+        next_height: BlockHeight,
+    ) -> bool {
+        if self.subscribers.len() >= MAX_SUBSCRIPTIONS {
+            warn!("Too many subscriptions, {} turned down", addr);
+            return false;
+        }
+        debug!("{} subscribed to {:?}", addr, subscription);
+        self.subscribers.push(Subscriber {
+            addr,
+            topic: subscription.topic,
+            next_height: subscription.start_height.unwrap_or(next_height),
+        });
+        true
+    }
+
+    /// Moves every subscriber up to `highest` committed height, at most a batch at a time,
+    /// and returns what is to be sent to whom. Subscribers whose connection is gone
+    /// according to `connected` are dropped.
+    pub fn poll(
+        &mut self,
+        highest: BlockHeight,
+        block_at: impl Fn(BlockHeight) -> Option<CommittedBlock>,
+        connected: impl Fn(SocketAddr) -> bool,
+    ) -> Vec<(SocketAddr, NodeMessage)> {
+        self.subscribers
+            .retain(|subscriber| connected(subscriber.addr));
+        let mut blocks = HashMap::new();
+        let mut messages = vec![];
+        let max_frame_length = self.max_frame_length;
+        for subscriber in &mut self.subscribers {
+            let end = highest.min(subscriber.next_height.saturating_add(CATCH_UP_BATCH - 1));
+            for height in subscriber.next_height..=end {
+                let block = blocks.entry(height).or_insert_with(|| block_at(height));
+                let Some(block) = block else {
+                    continue;
+                };
+                for message in notifications(&subscriber.topic, block) {
+                    // Sending it would close the connection.
+                    if !fits(&message, max_frame_length) {
+                        warn!(
+                            "Notification of block {} too large, skipped for {}",
+                            height, subscriber.addr
+                        );
+                        continue;
+                    }
+                    messages.push((subscriber.addr, message));
+                }
+            }
+            subscriber.next_height = subscriber.next_height.max(end.saturating_add(1));
+        }
+        messages
+    }
+
+    /// Whether some subscriber has not caught up with `highest` yet.
+    pub fn lagging(&self, highest: BlockHeight) -> bool {
+        self.subscribers
+            .iter()
+            .any(|subscriber| subscriber.next_height <= highest)
+    }
+}
+
+/// Whether `message` can be sent in a frame of at most `max_frame_length` bytes, a tag
+/// byte included.
+pub fn fits(message: &NodeMessage, max_frame_length: usize) -> bool {
+    message
+        .try_to_vec()
+        .is_ok_and(|bytes| bytes.len() < max_frame_length)
+}
+
+fn notifications(topic: &Topic, block: &CommittedBlock) -> Vec<NodeMessage> {
+    match topic {
+        Topic::Blocks => vec![NodeMessage::Block(block.clone())],
+        Topic::Requester(requester) => block
+            .transactions
+            .iter()
+            .filter(|request| request.requester == *requester)
+            .map(|request| {
+                NodeMessage::Transaction(CommittedTransaction {
+                    height: block.height,
+                    block: block.hash,
+                    request: request.clone(),
+                })
+            })
+            .collect(),
+        Topic::Events => block
+            .transactions
+            .iter()
+            .filter_map(|request| AppEvent::of(&request.data))
+            .map(|event| {
+                NodeMessage::Event(CommittedEvent {
+                    height: block.height,
+                    block: block.hash,
+                    event,
+                })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod feed_tests {
+    use super::*;
+    use dash_common::{
+        crypto::generate_keypair, governance::GovernanceTransaction, NewTransactionRequest,
+        TransactionData,
+    };
+
+    #[test]
+    fn catch_up_test() {
+        let alice = generate_keypair();
+        let bob = generate_keypair();
+        // One transaction per block, alternating requesters, a governance one at height 3.
+        let block_at = |height: BlockHeight| {
+            let keypair = if height.is_multiple_of(2) {
+                &alice
+            } else {
+                &bob
+            };
+            let data = match height {
+                3 => TransactionData::Governance(GovernanceTransaction::new(1, 0, vec![])),
+                _ => TransactionData::App(vec![height as u8]),
+            };
+            Some(CommittedBlock {
+                height,
+                hash: [height as u8; 32],
+                view: Some(height),
+                proposer: None,
+                transactions: vec![NewTransactionRequest::new(keypair, 1, data)],
+            })
+        };
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let gone: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+
+        let mut feeds = Feeds::new(1024 * 1024);
+        let subscribe = |topic, start_height| Subscription {
+            topic,
+            start_height,
+        };
+        feeds.subscribe(client, subscribe(Topic::Blocks, Some(0)), 5);
+        feeds.subscribe(client, subscribe(Topic::Blocks, None), 5);
+        feeds.subscribe(
+            client,
+            subscribe(Topic::Requester(alice.public.to_bytes()), Some(0)),
+            5,
+        );
+        feeds.subscribe(client, subscribe(Topic::Events, Some(0)), 5);
+        feeds.subscribe(gone, subscribe(Topic::Blocks, Some(0)), 5);
+
+        let messages = feeds.poll(4, block_at, |addr| addr == client);
+        let mut blocks = 0;
+        let mut transactions = vec![];
+        let mut events = vec![];
+        for (addr, message) in messages {
+            assert_eq!(addr, client);
+            match message {
+                NodeMessage::Block(_) => blocks += 1,
+                NodeMessage::Transaction(transaction) => transactions.push(transaction.height),
+                NodeMessage::Event(event) => events.push(event.height),
+                NodeMessage::Receipt(_) | NodeMessage::Headers(_) => unreachable!(),
+            }
+        }
+        // The live subscriber waits for height 5, the others catch up from 0.
+        assert_eq!(blocks, 5);
+        assert_eq!(transactions, vec![0, 2, 4]);
+        assert_eq!(events, vec![3]);
+        assert!(!feeds.lagging(4));
+
+        let messages = feeds.poll(5, block_at, |_| true);
+        assert_eq!(
+            messages
+                .iter()
+                .filter(|(_, message)| matches!(message, NodeMessage::Block(_)))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn large_block_test() {
+        let keypair = generate_keypair();
+        // The block at height 0 is too large to send.
+        let block_at = |height: BlockHeight| {
+            let size = if height == 0 { 20_000 } else { 10 };
+            let data = TransactionData::App(vec![0; size]);
+            Some(CommittedBlock {
+                height,
+                hash: [height as u8; 32],
+                view: Some(height),
+                proposer: None,
+                transactions: vec![NewTransactionRequest::new(&keypair, 1, data)],
+            })
+        };
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let mut feeds = Feeds::new(10_000);
+        for topic in [Topic::Blocks, Topic::Requester(keypair.public.to_bytes())] {
+            let subscription = Subscription {
+                topic,
+                start_height: Some(0),
+            };
+            feeds.subscribe(client, subscription, 0);
+        }
+
+        let heights: Vec<_> = feeds
+            .poll(1, block_at, |_| true)
+            .into_iter()
+            .map(|(_, message)| match message {
+                NodeMessage::Block(block) => block.height,
+                NodeMessage::Transaction(transaction) => transaction.height,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(heights, vec![1, 1]);
+        assert!(!feeds.lagging(1));
+    }
+}
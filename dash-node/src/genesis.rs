@@ -0,0 +1,220 @@
+use crate::config::PeerConfig;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::read_to_string;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine};
+use borsh::BorshSerialize;
+use hotstuff_rs::types::{
+    AppStateUpdates, ChainID, CryptoHash, Power, PublicKeyBytes, ValidatorSetUpdates,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The initial state of a chain, shared by all of its nodes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Genesis {
+    pub chain_id: ChainID,
+    /// Initial validators. Their addresses are where nodes first look for each other, they
+    /// are not part of the genesis hash.
+    pub validators: Vec<PeerConfig>,
+    /// Initial app state, base64 keys to base64 values.
+    #[serde(
+        default,
+        deserialize_with = "parse_app_state",
+        serialize_with = "serialize_app_state"
+    )]
+    pub app_state: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Identifies the chain on the wire, nodes only connect to nodes with the same hash.
+    #[serde(deserialize_with = "parse_hash", serialize_with = "serialize_hash")]
+    pub genesis_hash: CryptoHash,
+}
+
+impl Genesis {
+    pub fn new(
+        chain_id: ChainID,
+        validators: Vec<PeerConfig>,
+        app_state: BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> Self {
+        let mut genesis = Self {
+            chain_id,
+            validators,
+            app_state,
+            genesis_hash: CryptoHash::default(),
+        };
+        genesis.genesis_hash = genesis.hash();
+        genesis
+    }
+
+    /// Loads a genesis file and verifies it.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let genesis_str = read_to_string(path.as_ref())
+            .map_err(|e| anyhow!("Cannot read {}: {}", path.as_ref().display(), e))?;
+        let genesis = serde_yaml::from_str::<Genesis>(&genesis_str)?;
+        genesis.verify()?;
+        Ok(genesis)
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        if self.validators.is_empty() {
+            return Err(anyhow!("genesis has no validator"));
+        }
+        let mut keys = HashSet::new();
+        if !self
+            .validators
+            .iter()
+            .all(|peer| keys.insert(peer.public_key))
+        {
+            return Err(anyhow!("genesis lists a validator twice"));
+        }
+        if self.hash() != self.genesis_hash {
+            return Err(anyhow!(
+                "genesis hash mismatch, the genesis file was altered"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Hash over the chain ID, the validators with their power and the app state,
+    /// independent of the order they are listed in.
+    pub fn hash(&self) -> CryptoHash {
+        let mut validators: Vec<(PublicKeyBytes, Power)> = self
+            .validators
+            .iter()
+            .map(|peer| (peer.public_key, peer.power))
+            .collect();
+        validators.sort();
+        let bytes = (self.chain_id, validators, &self.app_state)
+            .try_to_vec()
+            .unwrap();
+        Sha256::digest(bytes).into()
+    }
+
+    pub fn peer_addresses(&self) -> HashMap<PublicKeyBytes, SocketAddr> {
+        self.validators
+            .iter()
+            .map(|peer| (peer.public_key, peer.host_addr))
+            .collect()
+    }
+
+    pub fn validator_set_updates(&self) -> ValidatorSetUpdates {
+        let mut updates = ValidatorSetUpdates::new();
+        for peer in &self.validators {
+            updates.insert(peer.public_key, peer.power);
+        }
+        updates
+    }
+
+    pub fn app_state_updates(&self) -> AppStateUpdates {
+        let mut updates = AppStateUpdates::new();
+        for (key, value) in &self.app_state {
+            updates.insert(key.clone(), value.clone());
+        }
+        updates
+    }
+}
+
+fn parse_app_state<'de, D>(d: D) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let app_state: BTreeMap<String, String> = Deserialize::deserialize(d)?;
+    let decode = |s: &str| {
+        general_purpose::STANDARD
+            .decode(s)
+            .map_err(serde::de::Error::custom)
+    };
+    app_state
+        .iter()
+        .map(|(key, value)| Ok((decode(key)?, decode(value)?)))
+        .collect()
+}
+
+fn serialize_app_state<S>(app_state: &BTreeMap<Vec<u8>, Vec<u8>>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let encoded: BTreeMap<String, String> = app_state
+        .iter()
+        .map(|(key, value)| {
+            (
+                general_purpose::STANDARD.encode(key),
+                general_purpose::STANDARD.encode(value),
+            )
+        })
+        .collect();
+    Serialize::serialize(&encoded, s)
+}
+
+fn parse_hash<'de, D>(d: D) -> Result<CryptoHash, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let hash: String = Deserialize::deserialize(d)?;
+    general_purpose::STANDARD
+        .decode(hash)
+        .map_err(serde::de::Error::custom)?
+        .try_into()
+        .map_err(|_| serde::de::Error::custom("genesis hash must be 32 bytes"))
+}
+
+fn serialize_hash<S>(hash: &CryptoHash, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    s.serialize_str(&general_purpose::STANDARD.encode(hash))
+}
+
+#[cfg(test)]
+mod genesis_tests {
+    use super::*;
+    use dash_common::crypto::generate_keypair;
+
+    #[test]
+    fn genesis_hash_test() {
+        let validators: Vec<_> = (0..2)
+            .map(|n| PeerConfig {
+                host_addr: format!("127.0.0.1:{}", 8080 + n).parse().unwrap(),
+                public_key: generate_keypair().public.to_bytes(),
+                power: 1,
+            })
+            .collect();
+        let app_state = BTreeMap::from([(b"key".to_vec(), b"value".to_vec())]);
+        let genesis = Genesis::new(7, validators.clone(), app_state.clone());
+        let yaml = serde_yaml::to_string(&genesis).unwrap();
+        let loaded = serde_yaml::from_str::<Genesis>(&yaml).unwrap();
+        loaded.verify().unwrap();
+        assert_eq!(loaded.app_state, app_state);
+
+        // Listing order and addresses do not change the chain.
+        let mut reordered = validators.clone();
+        reordered.reverse();
+        reordered[0].host_addr = "127.0.0.1:9000".parse().unwrap();
+        let other = Genesis::new(7, reordered, app_state.clone());
+        assert_eq!(other.genesis_hash, genesis.genesis_hash);
+
+        // Anything else does.
+        assert_ne!(
+            Genesis::new(8, validators.clone(), app_state).genesis_hash,
+            genesis.genesis_hash
+        );
+        let mut altered = loaded;
+        altered.validators[0].power = 2;
+        assert!(altered.verify().is_err());
+        altered.validators[0].power = 1;
+        altered.validators.push(validators[0].clone());
+        assert!(altered.verify().is_err());
+    }
+
+    #[test]
+    fn template_test() {
+        Genesis::from_path(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/config_template/genesis.yaml"
+        ))
+        .unwrap();
+    }
+}
@@ -1,5 +1,13 @@
+pub mod access;
+pub mod address_book;
 pub mod app;
 pub mod client_actor;
+pub mod commit_tracker;
 pub mod config;
+pub mod feed;
+pub mod genesis;
 pub mod kv_store;
 pub mod network;
+pub mod node;
+pub mod session;
+pub mod websocket;
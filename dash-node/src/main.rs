@@ -1,66 +1,44 @@
-use dash_node::{
-    app,
-    client_actor::ClientActor,
-    config::Config,
-    kv_store::KVStoreImpl,
-    network::{NetConfig, NetworkImpl},
-};
+use dash_node::{config::Config, node::Node};
 
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
 use anyhow::Result;
 use clap::Arg;
-use hotstuff_rs::{
-    pacemaker::DefaultPacemaker,
-    replica::Replica,
-    types::{AppStateUpdates, ValidatorSetUpdates},
-};
-use log::LevelFilter;
+use log::{info, warn, LevelFilter};
 use simple_logger::SimpleLogger;
-use tokio::{runtime::Builder, sync::mpsc::channel};
 
-fn main() -> Result<()> {
-    init_logger()?;
-    let config = init_config()?;
-
-    let kv_store = KVStoreImpl::default();
-    let rt = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
-    let (block_sender, block_receiver) = channel(1000);
-    let app = app::AppImpl::new(block_receiver);
-    let mut initial_validators = ValidatorSetUpdates::new();
-
-    config.validators.iter().for_each(|pubkey| {
-        initial_validators.insert(*pubkey, 1);
-    });
-
-    Replica::initialize(kv_store.clone(), AppStateUpdates::new(), initial_validators);
-    let keypair = config
-        .my_keypair
-        .expect("FATAL: my keypair not initialized!");
-    let public_key = keypair.public.to_bytes();
-    let net_config = NetConfig {
-        listen_addr: config.peer_listen_addr,
-        public_key,
-        initial_peers: config.peer_addresses,
-    };
-    let network = NetworkImpl::new(net_config, rt.clone());
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let pacemaker = DefaultPacemaker::new(
-        config.minimum_view_timeout,
-        config.sync_request_limit,
-        config.sync_response_timeout,
-    );
-    let _replica = Replica::start(app, keypair, network, kv_store, pacemaker);
-    ClientActor::spawn(
-        public_key,
-        config.client_listen_addr,
-        block_sender,
-        Arc::new(_replica),
-        rt,
-    );
+fn run() -> Result<()> {
+    init_logger()?;
+    let config_dir = config_dir()?;
+    let node = Node::start(Config::from_path(&config_dir)?)?;
     loop {
-        std::thread::sleep(std::time::Duration::from_secs(u64::MAX));
+        let signal = node.wait_for_signal()?;
+        if signal != "SIGHUP" {
+            info!("received {}, shutting down", signal);
+            break;
+        }
+        match Config::from_path(&config_dir) {
+            Ok(config) => {
+                node.reload(&config);
+                info!("received SIGHUP, config reloaded");
+            }
+            Err(e) => warn!("Reload config failed, keeping the current one: {}", e),
+        }
     }
+    node.shutdown()?;
+    info!("shut down cleanly");
+    Ok(())
 }
 
 fn init_logger() -> Result<()> {
@@ -72,7 +50,7 @@ fn init_logger() -> Result<()> {
     Ok(())
 }
 
-fn init_config() -> Result<Config> {
+fn config_dir() -> Result<PathBuf> {
     let args = clap::command!()
         .arg(
             Arg::new("config")
@@ -85,9 +63,8 @@ fn init_config() -> Result<Config> {
                 ),
         )
         .get_matches();
-    if let Some(path) = args.get_one::<String>("config") {
-        Config::from_path(path)
-    } else {
-        Config::new()
+    match args.get_one::<String>("config") {
+        Some(path) => Ok(path.into()),
+        None => Config::default_dir(),
     }
 }
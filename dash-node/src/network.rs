@@ -1,110 +1,359 @@
+use crate::address_book::{AddressBook, Announcement};
+use crate::config::PeerTransport;
 use dash_common::crypto::publickey_to_base64;
-use dash_network::{client, server};
+use dash_network::{
+    client, quic, server,
+    stats::{PeerStats, Stats, StatsSnapshot},
+    typed::typed,
+    Channel, Handle, Options,
+};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex, RwLock, TryLockError};
-use std::thread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
+use anyhow::Result;
 use borsh::{BorshDeserialize, BorshSerialize};
-use bytes::Bytes;
+use crossbeam_channel as queue;
 use hotstuff_rs::{
     messages::Message as InnerMessage,
     networking,
-    types::{PublicKeyBytes, ValidatorSet, ValidatorSetUpdates},
+    types::{DalekKeypair, PublicKeyBytes, ValidatorSet, ValidatorSetUpdates},
 };
-use log::warn;
+use log::{info, trace, warn};
 use tokio::{
     runtime::Runtime,
-    sync::mpsc::{channel, error::TryRecvError, Receiver, Sender},
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    time,
 };
+use tokio_util::sync::CancellationToken;
+
+/// Capacity of the queues of progress messages, in each direction. Inbound ones wait for
+/// the replica in a queue of their own as long again, and are dropped beyond.
+const PROGRESS_QUEUE_CAPACITY: usize = 1000;
+/// Capacity of the queues of sync messages, in each direction. Sync messages are dropped
+/// rather than waiting for room, the sync client retries on timeout.
+const SYNC_QUEUE_CAPACITY: usize = 100;
+/// How long the dispatcher waits before retrying to hand a progress message to the replica.
+const QUEUE_RETRY_DELAY: Duration = Duration::from_millis(1);
+/// Period of the announcements of this node's address to the other validators.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
 
 pub struct NetConfig {
     pub initial_peers: HashMap<PublicKeyBytes, SocketAddr>,
-    pub public_key: PublicKeyBytes,
+    /// Signs the announcements of this node's address.
+    pub keypair: DalekKeypair,
     pub listen_addr: SocketAddr,
+    pub options: Options,
+    pub transport: PeerTransport,
+    /// Period of the traffic statistics log.
+    pub stats_log_interval: Duration,
+    /// Follow the chain without taking part in consensus.
+    pub observer: bool,
+    /// Keys of the observers this node streams proposals to.
+    pub allowed_observers: HashSet<PublicKeyBytes>,
 }
 
 #[derive(Clone)]
 #[allow(dead_code)]
 pub struct NetworkImpl {
     validator_set: Arc<RwLock<ValidatorSet>>,
-    peer_addresses: Arc<HashMap<PublicKeyBytes, SocketAddr>>,
-    address_peers: Arc<HashMap<SocketAddr, PublicKeyBytes>>,
+    address_book: Arc<RwLock<AddressBook>>,
     my_publickey: PublicKeyBytes,
-    tx_sender: Sender<(PublicKeyBytes, Bytes)>,
-    rx_receiver: Arc<Mutex<Receiver<(PublicKeyBytes, Bytes)>>>,
+    observer: bool,
+    tx_senders: Lanes<Sender<(PublicKeyBytes, Message)>>,
+    rx_receivers: Lanes<queue::Receiver<(PublicKeyBytes, InnerMessage)>>,
+    loopback: Lanes<queue::Sender<(PublicKeyBytes, InnerMessage)>>,
+    inbound_errors: Arc<InboundErrors>,
     listen_addr: SocketAddr,
+    client_handle: Handle,
+    server_stats: Stats,
+    shutdown: CancellationToken,
+    dispatcher: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl NetworkImpl {
-    pub fn new(config: NetConfig, rt: Arc<Runtime>) -> Self {
-        let address_peers = Arc::new(
-            config
-                .initial_peers
-                .iter()
-                .map(|(key, addr)| (*addr, *key))
-                .collect::<HashMap<_, _>>(),
+    pub fn new(config: NetConfig, rt: Arc<Runtime>) -> Result<Self> {
+        let (server_channel, server_handle) = rt.block_on(async {
+            match config.transport {
+                PeerTransport::Tcp => {
+                    server::Server::spawn(config.listen_addr, config.options).await
+                }
+                PeerTransport::Quic => {
+                    quic::Server::spawn(config.listen_addr, config.options).await
+                }
+            }
+        })?;
+        let listen_addr = server_handle.local_addr().unwrap();
+        info!(
+            "listening for peers on {} over {:?}",
+            listen_addr, config.transport
         );
-        let peer_addresses = Arc::new(config.initial_peers);
+        let (client_channel, client_handle) = {
+            let _guard = rt.enter();
+            match config.transport {
+                PeerTransport::Tcp => client::Client::spawn(config.options),
+                PeerTransport::Quic => quic::Client::spawn(config.options)?,
+            }
+        };
 
-        let (tx_sender, tx_receiver) = channel(1000);
-        let (rx_sender, rx_receiver) = channel(1000);
+        let public_key = config.keypair.public.to_bytes();
+        // The bound address is of no use to others when listening on all interfaces, the
+        // address other validators know this node by is.
+        let announced_addr = config
+            .initial_peers
+            .get(&public_key)
+            .copied()
+            .unwrap_or(listen_addr);
+        let address_book = Arc::new(RwLock::new(AddressBook::new(
+            config.initial_peers,
+            config.allowed_observers,
+        )));
+
+        let (tx_senders, tx_receivers) = Lanes::channels();
+        let (rx_senders, rx_receivers) = Lanes::queues();
+        let inbound_errors = Arc::new(InboundErrors::default());
 
         let network = Self {
             validator_set: Arc::new(RwLock::new(ValidatorSet::new())),
-            peer_addresses: peer_addresses.clone(),
-            address_peers: address_peers.clone(),
-            my_publickey: config.public_key,
-            tx_sender,
-            rx_receiver: Arc::new(Mutex::new(rx_receiver)),
-            listen_addr: config.listen_addr,
+            address_book: address_book.clone(),
+            my_publickey: public_key,
+            observer: config.observer,
+            tx_senders,
+            rx_receivers,
+            loopback: rx_senders.clone(),
+            inbound_errors: inbound_errors.clone(),
+            listen_addr,
+            client_handle: client_handle.clone(),
+            server_stats: server_handle.stats().clone(),
+            shutdown: CancellationToken::new(),
+            dispatcher: Default::default(),
         };
 
-        thread::spawn(move || {
+        let shutdown = network.shutdown.clone();
+        let stats_network = network.clone();
+        let announcing_network = network.clone();
+        let dispatcher = thread::spawn(move || {
             rt.block_on(async {
-                dispatching(
-                    config.listen_addr,
-                    tx_receiver,
-                    rx_sender,
-                    peer_addresses,
-                    // address_peers,
-                )
-                .await;
+                tokio::select! {
+                    () = dispatching(
+                        server_channel,
+                        client_channel,
+                        tx_receivers,
+                        rx_senders,
+                        Inbound {
+                            my_publickey: public_key,
+                            errors: inbound_errors,
+                        },
+                        address_book,
+                        client_handle.clone(),
+                    ) => (),
+                    () = log_stats(stats_network, config.stats_log_interval) => (),
+                    () = announcing(announcing_network, config.keypair, announced_addr) => (),
+                    () = shutdown.cancelled() => (),
+                }
+                server_handle.shutdown().await;
+                client_handle.shutdown().await;
             });
         });
+        *network.dispatcher.lock().unwrap() = Some(dispatcher);
+
+        Ok(network)
+    }
+
+    /// Closes every peer connection and waits for the dispatching thread to exit. Messages
+    /// sent afterwards are dropped.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+        if let Some(dispatcher) = self.dispatcher.lock().unwrap().take() {
+            dispatcher.join().unwrap();
+        }
+        info!("peer network shut down");
+    }
+
+    /// Traffic and liveness of the outgoing connection to each peer.
+    pub fn peer_stats(&self) -> HashMap<PublicKeyBytes, PeerStats> {
+        let stats = self.client_handle.stats().peers();
+        self.address_book
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|(key, addr)| stats.get(addr).map(|stats| (*key, stats.clone())))
+            .collect()
+    }
+
+    /// Traffic of the incoming connections, keyed by the remote address since peers connect
+    /// from ephemeral ports.
+    pub fn inbound_stats(&self) -> StatsSnapshot {
+        self.server_stats.snapshot()
+    }
 
-        network
+    /// Inbound messages discarded before reaching the replica.
+    pub fn inbound_errors(&self) -> InboundErrorsSnapshot {
+        self.inbound_errors.snapshot()
+    }
+}
+
+async fn log_stats(network: NetworkImpl, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        for (key, stats) in network.peer_stats() {
+            info!("peer {}: {}", publickey_to_base64(key), stats);
+        }
+        let inbound = network.inbound_stats();
+        let (messages, bytes) = inbound
+            .peers
+            .values()
+            .fold((0, 0), |(messages, bytes), peer| {
+                (
+                    messages + peer.messages_received,
+                    bytes + peer.bytes_received,
+                )
+            });
+        info!(
+            "inbound: {} connections, received {} msgs/{} bytes, {} oversized frames, {} throttled",
+            inbound.peers.len(),
+            messages,
+            bytes,
+            inbound.oversized_frames,
+            inbound.throttled,
+        );
+        let errors = network.inbound_errors();
+        if errors != InboundErrorsSnapshot::default() {
+            warn!(
+                "inbound: {} malformed, {} misaddressed, {} progress and {} sync messages dropped",
+                errors.malformed, errors.misaddressed, errors.progress_dropped, errors.sync_dropped,
+            );
+        }
+    }
+}
+
+/// Tells every other validator where this node listens, at startup and then periodically so
+/// that validators joining later learn it too. Observers announce themselves as such, which
+/// keeps them known to the validators, and validators forget the nodes gone silent. Every
+/// announcement is signed anew, so that it supersedes the previous ones.
+async fn announcing(network: NetworkImpl, keypair: DalekKeypair, addr: SocketAddr) {
+    let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let announcement = Announcement::new(&keypair, addr);
+        let expired = network.address_book.write().unwrap().expire();
+        for addr in expired {
+            network.client_handle.disconnect(addr);
+        }
+        let peers: Vec<_> = network
+            .address_book
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|key| *key != network.my_publickey)
+            .collect();
+        for peer in peers {
+            let msg = Message {
+                from: network.my_publickey,
+                to: peer,
+                data: if network.observer {
+                    Payload::Observe(announcement.clone())
+                } else {
+                    Payload::Announce(announcement.clone())
+                },
+            };
+            if network.tx_senders.sync.send((peer, msg)).await.is_err() {
+                return;
+            }
+        }
     }
 }
 
 async fn dispatching(
-    listening_addr: SocketAddr,
-    mut tx_receiver: Receiver<(PublicKeyBytes, Bytes)>,
-    rx_sender: Sender<(PublicKeyBytes, Bytes)>,
-    peer_addresses: Arc<HashMap<PublicKeyBytes, SocketAddr>>,
-    // address_peers: Arc<HashMap<SocketAddr, PublicKeyBytes>>,
+    server_channel: Channel,
+    client_channel: Channel,
+    mut tx_receivers: Lanes<Receiver<(PublicKeyBytes, Message)>>,
+    rx_senders: Lanes<queue::Sender<(PublicKeyBytes, InnerMessage)>>,
+    inbound: Inbound,
+    address_book: Arc<RwLock<AddressBook>>,
+    client_handle: Handle,
 ) {
-    tokio::spawn(async move {
-        let (sender, _receiver) = client::Client::spawn();
-        while let Some((key, msg)) = tx_receiver.recv().await {
-            if let Some(addr) = peer_addresses.get(&key) {
-                sender.send((*addr, msg)).await.unwrap();
+    let outgoing_book = address_book.clone();
+    let outgoing = async move {
+        let (sender, _receiver) = typed::<Message, Message>(client_channel);
+        loop {
+            let (key, msg) = tokio::select! {
+                biased;
+                Some(msg) = tx_receivers.progress.recv() => msg,
+                Some(msg) = tx_receivers.sync.recv() => msg,
+                else => break,
+            };
+            let addr = outgoing_book.read().unwrap().get(&key);
+            if let Some(addr) = addr {
+                if let Err(e) = sender.send(addr, &msg).await {
+                    warn!("Send to {} failed: {}", addr, e);
+                }
             } else {
                 warn!("Cannot find addr of {}", publickey_to_base64(key));
             }
         }
-    });
-    tokio::spawn(async move {
-        let (_sender, mut receiver) = server::Server::spawn(listening_addr);
-        while let Some((_addr, msg)) = receiver.recv().await {
-            rx_sender.send((Default::default(), msg)).await.unwrap();
+    };
+    // Progress messages wait for the replica on their own, so that sync messages are not
+    // held up behind them.
+    let (parked_sender, mut parked_receiver) = channel(PROGRESS_QUEUE_CAPACITY);
+    let progress_sender = rx_senders.progress.clone();
+    let forwarding = async move {
+        while let Some(mut msg) = parked_receiver.recv().await {
+            // The replica polls the queue, wait for it to catch up when full.
+            while let Err(queue::TrySendError::Full(m)) = progress_sender.try_send(msg) {
+                msg = m;
+                time::sleep(QUEUE_RETRY_DELAY).await;
+            }
         }
-    });
-    loop {
-        tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
-    }
+    };
+    let incoming = async move {
+        let (_sender, mut receiver) = typed::<Message, Message>(server_channel);
+        while let Some((addr, msg)) = receiver.recv().await {
+            let (from, payload) = match inbound.validate(addr, msg) {
+                Some((from, Payload::Consensus(msg))) => (from, msg),
+                Some((_, Payload::Announce(announcement))) => {
+                    let moved = address_book.write().unwrap().announce(&announcement);
+                    if let Some(stale) = moved {
+                        client_handle.disconnect(stale);
+                    }
+                    continue;
+                }
+                Some((_, Payload::Observe(announcement))) => {
+                    let moved = address_book.write().unwrap().observe(&announcement);
+                    if let Some(stale) = moved {
+                        client_handle.disconnect(stale);
+                    }
+                    continue;
+                }
+                None => continue,
+            };
+            let msg = (from, payload);
+            match Lane::of(&msg.1) {
+                Lane::Progress => {
+                    if let Err(TrySendError::Full(_)) = parked_sender.try_send(msg) {
+                        inbound
+                            .errors
+                            .progress_dropped
+                            .fetch_add(1, Ordering::Relaxed);
+                        warn!("Progress queue full, message from {} dropped", addr);
+                    }
+                }
+                Lane::Sync => {
+                    if let Err(queue::TrySendError::Full(_)) = rx_senders.sync.try_send(msg) {
+                        inbound.errors.sync_dropped.fetch_add(1, Ordering::Relaxed);
+                        warn!("Sync queue full, message from {} dropped", addr);
+                    }
+                }
+            }
+        }
+    };
+    tokio::join!(outgoing, incoming, forwarding);
 }
 
 impl networking::Network for NetworkImpl {
@@ -114,6 +363,10 @@ impl networking::Network for NetworkImpl {
 
     fn update_validator_set(&mut self, updates: ValidatorSetUpdates) {
         self.validator_set.write().unwrap().apply_updates(&updates);
+        let removed = self.address_book.write().unwrap().update(&updates);
+        for addr in removed {
+            self.client_handle.disconnect(addr);
+        }
     }
 
     fn broadcast(&mut self, message: InnerMessage) {
@@ -127,44 +380,332 @@ impl networking::Network for NetworkImpl {
         for peer in validators {
             networking::Network::send(self, peer, message.clone());
         }
+        // Observers get proposals on the sync lane, they catch up on what they miss.
+        let observers: Vec<_> = self
+            .address_book
+            .read()
+            .unwrap()
+            .observers()
+            .copied()
+            .collect();
+        for peer in observers {
+            let msg = Message {
+                from: self.my_publickey,
+                to: peer,
+                data: Payload::Consensus(message.clone()),
+            };
+            if self.tx_senders.sync.try_send((peer, msg)).is_err() {
+                trace!(
+                    "Sync queue full, message to observer {} dropped",
+                    publickey_to_base64(peer)
+                );
+            }
+        }
     }
 
     fn send(&mut self, peer: PublicKeyBytes, message: InnerMessage) {
+        if peer == self.my_publickey {
+            self.deliver_locally(message);
+            return;
+        }
+        let lane = Lane::of(&message);
+        // An observer never votes, nor asks for a new view.
+        if self.observer && matches!(lane, Lane::Progress) {
+            trace!("observer, message to {} dropped", publickey_to_base64(peer));
+            return;
+        }
         let msg = Message {
             from: self.my_publickey,
             to: peer,
-            data: message,
+            data: Payload::Consensus(message),
+        };
+        // Progress messages wait for room so that none is lost, sync messages never hold up
+        // the replica thread.
+        let result = match lane {
+            Lane::Progress => self
+                .tx_senders
+                .progress
+                .blocking_send((peer, msg))
+                .map_err(|e| TrySendError::Closed(e.0)),
+            Lane::Sync => self.tx_senders.sync.try_send((peer, msg)),
+        };
+        match result {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => warn!(
+                "Sync queue full, message to {} dropped",
+                publickey_to_base64(peer)
+            ),
+            Err(TrySendError::Closed(_)) => trace!(
+                "network is shut down, message to {} dropped",
+                publickey_to_base64(peer)
+            ),
         }
-        .try_to_vec()
-        .unwrap()
-        .into();
-        self.tx_sender.blocking_send((peer, msg)).unwrap();
     }
 
     fn recv(&mut self) -> Option<(PublicKeyBytes, InnerMessage)> {
-        let mut chan = match self.rx_receiver.try_lock() {
-            Ok(chan) => chan,
-            Err(TryLockError::WouldBlock) => return None,
-            Err(e) => panic!("{:?}", e),
+        self.rx_receivers
+            .progress
+            .try_recv()
+            .or_else(|_| self.rx_receivers.sync.try_recv())
+            .ok()
+    }
+}
+
+impl NetworkImpl {
+    /// Puts a message addressed to this node straight into its inbound queue. The replica
+    /// thread is the one draining that queue, so a message that finds it full is dropped
+    /// rather than waited on.
+    fn deliver_locally(&self, message: InnerMessage) {
+        let lane = match Lane::of(&message) {
+            Lane::Progress => &self.loopback.progress,
+            Lane::Sync => &self.loopback.sync,
         };
-        match chan.try_recv() {
-            Ok((_, data)) => {
-                let Message { from, to, data } = Message::deserialize(&mut data.as_ref()).unwrap();
-                if to != self.my_publickey {
-                    warn!("Not my message, droped!");
-                }
-                Some((from, data))
+        match lane.try_send((self.my_publickey, message)) {
+            Ok(()) => (),
+            Err(queue::TrySendError::Full(_)) => warn!("Inbound queue full, own message dropped"),
+            Err(queue::TrySendError::Disconnected(_)) => {
+                trace!("network is shut down, own message dropped")
             }
-            Err(TryRecvError::Empty) => None,
-            Err(e) => panic!("{:?}", e),
         }
     }
 }
 
+/// Decodes and validates messages on the tokio side, so that the replica only receives
+/// messages meant for it.
+struct Inbound {
+    my_publickey: PublicKeyBytes,
+    errors: Arc<InboundErrors>,
+}
+
+impl Inbound {
+    fn validate(
+        &self,
+        addr: SocketAddr,
+        msg: std::io::Result<Message>,
+    ) -> Option<(PublicKeyBytes, Payload)> {
+        match msg {
+            Ok(Message { from, to, data }) if to == self.my_publickey => Some((from, data)),
+            Ok(Message { to, .. }) => {
+                self.errors.misaddressed.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Message from {} addressed to {}, dropped",
+                    addr,
+                    publickey_to_base64(to)
+                );
+                None
+            }
+            Err(e) => {
+                self.errors.malformed.fetch_add(1, Ordering::Relaxed);
+                warn!("Malformed message from {}: {}", addr, e);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct InboundErrors {
+    malformed: AtomicU64,
+    misaddressed: AtomicU64,
+    progress_dropped: AtomicU64,
+    sync_dropped: AtomicU64,
+}
+
+impl InboundErrors {
+    fn snapshot(&self) -> InboundErrorsSnapshot {
+        InboundErrorsSnapshot {
+            malformed: self.malformed.load(Ordering::Relaxed),
+            misaddressed: self.misaddressed.load(Ordering::Relaxed),
+            progress_dropped: self.progress_dropped.load(Ordering::Relaxed),
+            sync_dropped: self.sync_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InboundErrorsSnapshot {
+    /// Messages that failed to decode.
+    pub malformed: u64,
+    /// Messages addressed to another node.
+    pub misaddressed: u64,
+    /// Progress messages dropped because the replica fell far behind.
+    pub progress_dropped: u64,
+    /// Sync messages dropped because the replica fell behind.
+    pub sync_dropped: u64,
+}
+
+/// Consensus messages are queued apart from block sync messages, so that a replica catching
+/// up on history does not delay votes.
+enum Lane {
+    Progress,
+    Sync,
+}
+
+impl Lane {
+    fn of(msg: &InnerMessage) -> Self {
+        match msg {
+            InnerMessage::ProgressMessage(_) => Lane::Progress,
+            InnerMessage::SyncMessage(_) => Lane::Sync,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Lanes<T> {
+    progress: T,
+    sync: T,
+}
+
+impl<T> Lanes<T> {
+    fn channels() -> (Lanes<Sender<T>>, Lanes<Receiver<T>>) {
+        let (progress_sender, progress_receiver) = channel(PROGRESS_QUEUE_CAPACITY);
+        let (sync_sender, sync_receiver) = channel(SYNC_QUEUE_CAPACITY);
+        (
+            Lanes {
+                progress: progress_sender,
+                sync: sync_sender,
+            },
+            Lanes {
+                progress: progress_receiver,
+                sync: sync_receiver,
+            },
+        )
+    }
+
+    /// Lock-free queues, polled by the replica thread without blocking.
+    fn queues() -> (Lanes<queue::Sender<T>>, Lanes<queue::Receiver<T>>) {
+        let (progress_sender, progress_receiver) = queue::bounded(PROGRESS_QUEUE_CAPACITY);
+        let (sync_sender, sync_receiver) = queue::bounded(SYNC_QUEUE_CAPACITY);
+        (
+            Lanes {
+                progress: progress_sender,
+                sync: sync_sender,
+            },
+            Lanes {
+                progress: progress_receiver,
+                sync: sync_receiver,
+            },
+        )
+    }
+}
+
 // TODO: add Signature
 #[derive(BorshDeserialize, BorshSerialize)]
 struct Message {
     from: PublicKeyBytes,
     to: PublicKeyBytes,
-    data: InnerMessage,
+    data: Payload,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+enum Payload {
+    Consensus(InnerMessage),
+    Announce(Announcement),
+    /// Announcement of an observer, which validators send proposals and sync responses to.
+    Observe(Announcement),
+}
+
+#[cfg(test)]
+mod network_tests {
+    use super::*;
+    use hotstuff_rs::messages::{NewView, ProgressMessage, SyncMessage, SyncRequest};
+    use hotstuff_rs::types::QuorumCertificate;
+
+    #[test]
+    fn inbound_validation_test() {
+        let inbound = Inbound {
+            my_publickey: [1; 32],
+            errors: Default::default(),
+        };
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let message = |to| Message {
+            from: [2; 32],
+            to,
+            data: Payload::Consensus(InnerMessage::SyncMessage(SyncMessage::SyncRequest(
+                SyncRequest {
+                    start_height: 0,
+                    limit: 1,
+                },
+            ))),
+        };
+
+        assert!(inbound.validate(addr, Ok(message([1; 32]))).is_some());
+        assert!(inbound.validate(addr, Ok(message([3; 32]))).is_none());
+        assert!(inbound
+            .validate(addr, Err(std::io::ErrorKind::InvalidData.into()))
+            .is_none());
+        assert_eq!(
+            inbound.errors.snapshot(),
+            InboundErrorsSnapshot {
+                malformed: 1,
+                misaddressed: 1,
+                progress_dropped: 0,
+                sync_dropped: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn lanes_test() {
+        let (incoming, server_receiver) = channel(10_000);
+        let (server_sender, _outgoing) = channel(1);
+        let (client_sender, client_receiver) = channel(1);
+        let (_tx_senders, tx_receivers) = Lanes::channels();
+        let (rx_senders, rx_receivers) = Lanes::queues();
+        let errors = Arc::new(InboundErrors::default());
+        let (_, client_handle) = client::Client::spawn(Default::default());
+        tokio::spawn(dispatching(
+            (server_sender, server_receiver),
+            (client_sender, client_receiver),
+            tx_receivers,
+            rx_senders,
+            Inbound {
+                my_publickey: [1; 32],
+                errors: errors.clone(),
+            },
+            Arc::new(RwLock::new(AddressBook::new(
+                HashMap::new(),
+                HashSet::new(),
+            ))),
+            client_handle,
+        ));
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let send = |message| {
+            let bytes = Message {
+                from: [2; 32],
+                to: [1; 32],
+                data: Payload::Consensus(message),
+            }
+            .try_to_vec()
+            .unwrap();
+            incoming.try_send((addr, bytes.into())).unwrap();
+        };
+
+        // The replica does not poll, progress messages pile up until dropped.
+        for _ in 0..PROGRESS_QUEUE_CAPACITY * 2 + 10 {
+            send(InnerMessage::ProgressMessage(ProgressMessage::NewView(
+                NewView {
+                    chain_id: 1,
+                    view: 1,
+                    highest_qc: QuorumCertificate::genesis_qc(),
+                },
+            )));
+        }
+        send(InnerMessage::SyncMessage(SyncMessage::SyncRequest(
+            SyncRequest {
+                start_height: 0,
+                limit: 1,
+            },
+        )));
+        // Sync messages still get through.
+        time::timeout(Duration::from_secs(5), async {
+            while rx_receivers.sync.is_empty() || !rx_receivers.progress.is_full() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // One may be held by the forwarder on top of both full queues.
+        assert!(errors.progress_dropped.load(Ordering::Relaxed) >= 9);
+    }
 }
@@ -0,0 +1,689 @@
+use crate::{
+    app::AppImpl,
+    client_actor::{ClientActor, ClientActorConfig},
+    config::Config,
+    kv_store::KVStoreImpl,
+    network::{NetConfig, NetworkImpl},
+    websocket::{WebSocketConfig, WebSocketGateway},
+};
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use hotstuff_rs::{pacemaker::DefaultPacemaker, replica::Replica, types::DalekKeypair};
+use log::{info, warn};
+use tokio::{
+    runtime::{Builder, Runtime},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::channel,
+};
+use tokio_util::sync::CancellationToken;
+
+/// A running validator, or observer: consensus, the peer network, the client service and
+/// optionally the dashboard service.
+pub struct Node {
+    rt: Arc<Runtime>,
+    network: NetworkImpl,
+    replica: Arc<Replica<KVStoreImpl>>,
+    client_actor: ClientActor,
+    websocket: Option<WebSocketGateway>,
+    app_shutdown: CancellationToken,
+}
+
+impl Node {
+    pub fn start(mut config: Config) -> Result<Self> {
+        let genesis = config
+            .genesis
+            .as_ref()
+            .ok_or_else(|| anyhow!("genesis not loaded"))?;
+        let kv_store = KVStoreImpl::default();
+        let rt = Arc::new(Builder::new_multi_thread().enable_all().build()?);
+        let (block_sender, block_receiver) = channel(1000);
+        let app_shutdown = CancellationToken::new();
+        let app = AppImpl::new(genesis.chain_id, block_receiver, app_shutdown.clone());
+        Replica::initialize(
+            kv_store.clone(),
+            genesis.app_state_updates(),
+            genesis.validator_set_updates(),
+        );
+        let initial_peers = genesis.peer_addresses();
+        let keypair = config
+            .my_keypair
+            .take()
+            .ok_or_else(|| anyhow!("my keypair not initialized"))?;
+        let public_key = keypair.public.to_bytes();
+        if config.observer && genesis.peer_addresses().contains_key(&public_key) {
+            return Err(anyhow!("an observer must not be a genesis validator"));
+        }
+        // The client service signs receipts with the validator key too.
+        let receipt_keypair = DalekKeypair::from_bytes(&keypair.to_bytes())?;
+        let net_config = NetConfig {
+            listen_addr: config.peer_listen_addr,
+            keypair: DalekKeypair::from_bytes(&keypair.to_bytes())?,
+            initial_peers,
+            options: config.peer_options(),
+            transport: config.peer_transport,
+            stats_log_interval: config.stats_log_interval,
+            observer: config.observer,
+            allowed_observers: config
+                .observer_allowlist
+                .iter()
+                .flatten()
+                .copied()
+                .collect(),
+        };
+        let network = NetworkImpl::new(net_config, rt.clone())?;
+
+        let pacemaker = DefaultPacemaker::new(
+            config.minimum_view_timeout,
+            config.sync_request_limit,
+            config.sync_response_timeout,
+        );
+        let replica = Arc::new(Replica::start(
+            app,
+            keypair,
+            network.clone(),
+            kv_store.clone(),
+            pacemaker,
+        ));
+        let client_config = ClientActorConfig {
+            chain_id: genesis.chain_id,
+            keypair: receipt_keypair,
+            listen_addr: config.client_listen_addr,
+            options: config.client_options(),
+            drain_timeout: config.shutdown_timeout,
+            retention: config.session_retention(),
+            pending_timeout: config.client_pending_timeout,
+            max_in_flight: config.client_max_in_flight,
+            retry_after: config.client_retry_after,
+            access: config.access_policy(),
+            relay_ip: config
+                .websocket_listen_addr
+                .map(|_| relay_ip(config.client_listen_addr)),
+            observer: config.observer,
+        };
+        let client_actor =
+            ClientActor::spawn(client_config, block_sender, replica.clone(), rt.clone())?;
+        let websocket = match config.websocket_listen_addr {
+            Some(listen_addr) => {
+                let websocket_config = WebSocketConfig {
+                    chain_id: genesis.chain_id,
+                    pubkey: public_key,
+                    listen_addr,
+                    client_addr: client_actor.local_addr(),
+                    client_options: config.relay_options(),
+                    max_connections: config.websocket_max_connections,
+                    max_connections_per_ip: config.websocket_max_connections_per_ip,
+                    ip_rate_limit: config.client_ip_rate_limit,
+                };
+                Some(WebSocketGateway::spawn(
+                    websocket_config,
+                    replica.clone(),
+                    network.clone(),
+                    rt.clone(),
+                )?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            rt,
+            network,
+            replica,
+            client_actor,
+            websocket,
+            app_shutdown,
+        })
+    }
+
+    /// Where dashboards connect, if they are served.
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.websocket.as_ref().map(WebSocketGateway::local_addr)
+    }
+
+    /// Blocks until the process receives SIGINT, SIGTERM or SIGHUP, returns the signal
+    /// name.
+    pub fn wait_for_signal(&self) -> Result<&'static str> {
+        Ok(self.rt.block_on(signal_received())?)
+    }
+
+    /// Applies the reloadable part of a new config: who may submit transactions and how
+    /// fast.
+    pub fn reload(&self, config: &Config) {
+        self.client_actor.update_access(config.access_policy());
+        if let Some(websocket) = &self.websocket {
+            websocket.update_ip_rate_limit(config.client_ip_rate_limit);
+        }
+    }
+
+    /// Stops the node in dependency order: the client service stops taking transactions and
+    /// empty blocks are proposed to commit the pending ones, their receipts are delivered
+    /// while consensus runs, dashboards included. Then the dashboards, consensus, and the
+    /// peer network. Fails if some pending transaction got no receipt.
+    pub fn shutdown(self) -> Result<()> {
+        self.client_actor.stop_accepting();
+        self.app_shutdown.cancel();
+        let undelivered = self.client_actor.shutdown();
+        if let Some(websocket) = self.websocket {
+            websocket.shutdown();
+        }
+        // The last reference, dropping it joins the consensus threads. The store is in
+        // memory, once they are gone no write batch is left half applied.
+        drop(self.replica);
+        info!("consensus stopped");
+        self.network.shutdown();
+        if undelivered > 0 {
+            warn!("{} transactions left without a receipt", undelivered);
+            return Err(anyhow!("{} receipts undelivered", undelivered));
+        }
+        Ok(())
+    }
+}
+
+async fn signal_received() -> io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res.map(|()| "SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+        _ = hangup.recv() => Ok("SIGHUP"),
+    }
+}
+
+/// Source address of the connections the dashboard gateway opens to the client service.
+fn relay_ip(client_addr: SocketAddr) -> IpAddr {
+    match client_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod node_tests {
+    use super::*;
+    use crate::config::{PeerConfig, PeerTransport};
+    use crate::genesis::Genesis;
+    use base64::{engine::general_purpose, Engine};
+    use borsh::BorshSerialize;
+    use dash_common::{
+        crypto,
+        governance::{GovernanceTransaction, ValidatorChange, GOVERNANCE_NONCE_KEY},
+        light_client::LightClient,
+        proof::HeaderRequest,
+        ClientMessage, NewTransactionRequest, NodeMessage, TransactionData, TransactionResult,
+    };
+    use dash_network::{
+        client::Client,
+        typed::{typed, TypedReceiver, TypedSender},
+        Handle,
+    };
+    use futures::{SinkExt, StreamExt};
+    use hotstuff_rs::types::{ChainID, PublicKeyBytes, ValidatorSet};
+    use tokio_tungstenite::tungstenite::Message;
+
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    /// A single validator talks only to itself, its address is never used.
+    fn single_validator_genesis(public_key: PublicKeyBytes) -> Genesis {
+        let validator = PeerConfig {
+            host_addr: "127.0.0.1:0".parse().unwrap(),
+            public_key,
+            power: 1,
+        };
+        Genesis::new(1, vec![validator], BTreeMap::new())
+    }
+
+    #[test]
+    fn shutdown_test() {
+        for transport in [PeerTransport::Tcp, PeerTransport::Quic] {
+            let mut config = serde_yaml::from_str::<Config>(
+                "peer_listen_addr: 127.0.0.1:0\n\
+                 client_listen_addr: 127.0.0.1:0\n\
+                 minimum_view_timeout_ms: 100\n\
+                 sync_request_limit: 10\n\
+                 sync_response_timeout_ms: 100\n",
+            )
+            .unwrap();
+            let keypair = crypto::generate_keypair();
+            let public_key = keypair.public.to_bytes();
+            config.peer_transport = transport;
+            config.my_keypair = Some(keypair);
+            config.genesis = Some(single_validator_genesis(public_key));
+
+            let node = Node::start(config).unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            node.shutdown().unwrap();
+        }
+    }
+
+    /// A single validator serving clients, with its keypair. `extra_config` is appended to
+    /// the config file.
+    fn start_single_validator(extra_config: &str) -> (Node, DalekKeypair) {
+        let mut config = serde_yaml::from_str::<Config>(&format!(
+            "peer_listen_addr: 127.0.0.1:0\n\
+             client_listen_addr: 127.0.0.1:0\n\
+             minimum_view_timeout_ms: 100\n\
+             sync_request_limit: 10\n\
+             sync_response_timeout_ms: 100\n{}",
+            extra_config
+        ))
+        .unwrap();
+        let keypair = crypto::generate_keypair();
+        config.genesis = Some(single_validator_genesis(keypair.public.to_bytes()));
+        config.my_keypair = Some(DalekKeypair::from_bytes(&keypair.to_bytes()).unwrap());
+        (Node::start(config).unwrap(), keypair)
+    }
+
+    /// A client of `node`, and what it sends and receives through.
+    fn connect(
+        node: &Node,
+    ) -> (
+        TypedSender<ClientMessage>,
+        TypedReceiver<NodeMessage>,
+        Handle,
+    ) {
+        let (channel, handle) = {
+            let _guard = node.rt.enter();
+            Client::spawn(Default::default())
+        };
+        let (sender, receiver) = typed::<ClientMessage, NodeMessage>(channel);
+        (sender, receiver, handle)
+    }
+
+    /// Gives the sole validator a power of 5.
+    fn power_change(validator: &DalekKeypair, chain_id: ChainID) -> NewTransactionRequest {
+        let mut transaction = GovernanceTransaction::new(
+            1,
+            0,
+            vec![ValidatorChange::Put {
+                public_key: validator.public.to_bytes(),
+                power: 5,
+            }],
+        );
+        transaction.sign(validator);
+        NewTransactionRequest::new(
+            &crypto::generate_keypair(),
+            chain_id,
+            TransactionData::Governance(transaction),
+        )
+    }
+
+    fn genesis_validator_set(public_key: PublicKeyBytes) -> ValidatorSet {
+        let mut validator_set = ValidatorSet::new();
+        validator_set.put(&public_key, 1);
+        validator_set
+    }
+
+    fn next_message(node: &Node, receiver: &mut TypedReceiver<NodeMessage>) -> NodeMessage {
+        let (_, message) = node.rt.block_on(receiver.recv()).unwrap();
+        message.unwrap()
+    }
+
+    #[test]
+    fn governance_test() {
+        let (node, keypair) = start_single_validator("");
+        let public_key = keypair.public.to_bytes();
+        let (sender, _receiver, handle) = connect(&node);
+        let client_addr = node.client_actor.local_addr();
+        let request = ClientMessage::Transaction(power_change(&keypair, 1));
+        node.rt
+            .block_on(sender.send(client_addr, &request))
+            .unwrap();
+
+        let mut power = None;
+        for _ in 0..100 {
+            power = node
+                .replica
+                .block_tree_camera()
+                .snapshot()
+                .committed_validator_set()
+                .power(&public_key)
+                .copied();
+            if power == Some(5) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(power, Some(5));
+        let rt = node.rt.clone();
+        node.shutdown().unwrap();
+        rt.block_on(handle.shutdown());
+    }
+
+    #[test]
+    fn foreign_chain_test() {
+        let (node, keypair) = start_single_validator("");
+        let (sender, mut receiver, handle) = connect(&node);
+        let client_addr = node.client_actor.local_addr();
+        let request = ClientMessage::Transaction(power_change(&keypair, 2));
+        node.rt
+            .block_on(sender.send(client_addr, &request))
+            .unwrap();
+
+        let NodeMessage::Receipt(receipt) = next_message(&node, &mut receiver) else {
+            panic!("expected a receipt");
+        };
+        assert!(matches!(
+            receipt.result,
+            TransactionResult::Unaccepted { .. }
+        ));
+        let rt = node.rt.clone();
+        node.shutdown().unwrap();
+        rt.block_on(handle.shutdown());
+    }
+
+    #[test]
+    fn receipt_test() {
+        let (node, keypair) = start_single_validator("");
+        let public_key = keypair.public.to_bytes();
+        let (sender, mut receiver, handle) = connect(&node);
+        let client_addr = node.client_actor.local_addr();
+        // Blocks are only proposed with transactions, the first one commits once three more
+        // are on top of it.
+        let client_keypair = crypto::generate_keypair();
+        let requests: Vec<_> = (0..4)
+            .map(|n| NewTransactionRequest::new(&client_keypair, 1, TransactionData::App(vec![n])))
+            .collect();
+        for request in &requests {
+            let message = ClientMessage::Transaction(request.clone());
+            node.rt
+                .block_on(sender.send(client_addr, &message))
+                .unwrap();
+        }
+
+        let NodeMessage::Receipt(receipt) = next_message(&node, &mut receiver) else {
+            panic!("expected a receipt");
+        };
+        assert_eq!(receipt.result, TransactionResult::Commited);
+        assert_eq!(receipt.receiptor, public_key);
+        assert_eq!(receipt.hash, requests[0].hash);
+        receipt.verify(1).unwrap();
+        let proof = receipt.proof.expect("a commit proof");
+        proof
+            .verify(1, &genesis_validator_set(public_key), &receipt.hash)
+            .unwrap();
+        let rt = node.rt.clone();
+        node.shutdown().unwrap();
+        rt.block_on(handle.shutdown());
+    }
+
+    #[test]
+    fn light_client_test() {
+        let (node, keypair) = start_single_validator("");
+        let public_key = keypair.public.to_bytes();
+        let (sender, mut receiver, handle) = connect(&node);
+        let client_addr = node.client_actor.local_addr();
+        let request = ClientMessage::Transaction(power_change(&keypair, 1));
+        node.rt
+            .block_on(sender.send(client_addr, &request))
+            .unwrap();
+        let NodeMessage::Receipt(receipt) = next_message(&node, &mut receiver) else {
+            panic!("expected a receipt");
+        };
+        assert_eq!(receipt.result, TransactionResult::Commited);
+
+        // A light client following the chain from genesis picks up the change.
+        let request = HeaderRequest {
+            start_height: 0,
+            count: 100,
+        };
+        node.rt
+            .block_on(sender.send(client_addr, &ClientMessage::Headers(request)))
+            .unwrap();
+        let NodeMessage::Headers(chain) = next_message(&node, &mut receiver) else {
+            panic!("expected headers");
+        };
+        let mut light_client =
+            LightClient::new(1, genesis_validator_set(public_key), BTreeMap::new());
+        light_client.advance(&chain).unwrap();
+        assert_eq!(light_client.validator_set().power(&public_key), Some(&5));
+        assert_eq!(
+            light_client.app_state(GOVERNANCE_NONCE_KEY),
+            Some(1u64.try_to_vec().unwrap())
+        );
+        let rt = node.rt.clone();
+        node.shutdown().unwrap();
+        rt.block_on(handle.shutdown());
+    }
+
+    #[test]
+    fn header_paging_test() {
+        let (node, keypair) = start_single_validator("client_max_send_frame_length: 100000\n");
+        let public_key = keypair.public.to_bytes();
+        let (sender, mut receiver, handle) = connect(&node);
+        let client_addr = node.client_actor.local_addr();
+        // Three of them commit, more than a client may send in a frame and than the node
+        // may send in one.
+        let client_keypair = crypto::generate_keypair();
+        for n in 0..6 {
+            let data = TransactionData::App(vec![n; 40_000]);
+            let request = NewTransactionRequest::new(&client_keypair, 1, data);
+            let message = ClientMessage::Transaction(request);
+            node.rt
+                .block_on(sender.send(client_addr, &message))
+                .unwrap();
+        }
+        let mut highest = 0;
+        for _ in 0..3 {
+            let NodeMessage::Receipt(receipt) = next_message(&node, &mut receiver) else {
+                panic!("expected a receipt");
+            };
+            highest = highest.max(receipt.proof.expect("a commit proof").height());
+        }
+
+        let mut light_client =
+            LightClient::new(1, genesis_validator_set(public_key), BTreeMap::new());
+        let mut pages = 0;
+        while light_client.height() < Some(highest) {
+            let request = HeaderRequest {
+                start_height: light_client.next_height(),
+                count: 100,
+            };
+            node.rt
+                .block_on(sender.send(client_addr, &ClientMessage::Headers(request)))
+                .unwrap();
+            let NodeMessage::Headers(chain) = next_message(&node, &mut receiver) else {
+                panic!("expected headers");
+            };
+            light_client.advance(&chain).unwrap();
+            pages += 1;
+        }
+        assert!(pages > 1);
+        let rt = node.rt.clone();
+        node.shutdown().unwrap();
+        rt.block_on(handle.shutdown());
+    }
+
+    #[test]
+    fn observer_test() {
+        let free_addr = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let config = |peer_addr, client_addr, observer| {
+            serde_yaml::from_str::<Config>(&format!(
+                "peer_listen_addr: {}\n\
+                 client_listen_addr: {}\n\
+                 observer: {}\n\
+                 minimum_view_timeout_ms: 100\n\
+                 sync_request_limit: 10\n\
+                 sync_response_timeout_ms: 100\n\
+                 shutdown_timeout_ms: 5000\n",
+                peer_addr, client_addr, observer
+            ))
+            .unwrap()
+        };
+        let (validator_addr, validator_client_addr) = (free_addr(), free_addr());
+        let observer_client_addr = free_addr();
+        let keypair = crypto::generate_keypair();
+        let public_key = keypair.public.to_bytes();
+        let genesis = Genesis::new(
+            1,
+            vec![PeerConfig {
+                host_addr: validator_addr,
+                public_key,
+                power: 1,
+            }],
+            BTreeMap::new(),
+        );
+
+        // An observer with a validator key is turned down.
+        let mut observer_config =
+            config("127.0.0.1:0".parse().unwrap(), observer_client_addr, true);
+        observer_config.genesis = Some(genesis.clone());
+        observer_config.my_keypair = Some(DalekKeypair::from_bytes(&keypair.to_bytes()).unwrap());
+        assert!(Node::start(observer_config).is_err());
+
+        // The validator only streams proposals to the observers it lists.
+        let observer_keypair = crypto::generate_keypair();
+        let mut validator_config = config(validator_addr, validator_client_addr, false);
+        validator_config.genesis = Some(genesis.clone());
+        validator_config.my_keypair = Some(keypair);
+        validator_config.observer_allowlist = Some(vec![observer_keypair.public.to_bytes()]);
+        let validator = Node::start(validator_config).unwrap();
+        let mut observer_config = config(free_addr(), observer_client_addr, true);
+        observer_config.genesis = Some(genesis);
+        observer_config.my_keypair = Some(observer_keypair);
+        let observer = Node::start(observer_config).unwrap();
+
+        let rt = validator.rt.clone();
+        let (channel, handle) = {
+            let _guard = rt.enter();
+            Client::spawn(Default::default())
+        };
+        let (sender, mut receiver) = typed::<ClientMessage, NodeMessage>(channel);
+        let client_keypair = crypto::generate_keypair();
+        // The first block commits once three more are on top of it.
+        for n in 0..4 {
+            let request =
+                NewTransactionRequest::new(&client_keypair, 1, TransactionData::App(vec![n]));
+            rt.block_on(sender.send(validator_client_addr, &ClientMessage::Transaction(request)))
+                .unwrap();
+        }
+        let mut committed = None;
+        for _ in 0..100 {
+            committed = observer
+                .replica
+                .block_tree_camera()
+                .snapshot()
+                .highest_committed_block();
+            if committed.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(committed.is_some(), "the observer did not follow the chain");
+
+        // Observers serve the committed chain but take no transaction.
+        let request = HeaderRequest {
+            start_height: 0,
+            count: 100,
+        };
+        rt.block_on(sender.send(observer_client_addr, &ClientMessage::Headers(request)))
+            .unwrap();
+        let request = NewTransactionRequest::new(&client_keypair, 1, TransactionData::App(vec![9]));
+        rt.block_on(sender.send(observer_client_addr, &ClientMessage::Transaction(request)))
+            .unwrap();
+        let (mut headers, mut rejected) = (false, false);
+        while !(headers && rejected) {
+            let (addr, message) = rt.block_on(receiver.recv()).unwrap();
+            match message.unwrap() {
+                NodeMessage::Headers(chain) => {
+                    assert_eq!(addr, observer_client_addr);
+                    let mut validator_set = ValidatorSet::new();
+                    validator_set.put(&public_key, 1);
+                    LightClient::new(1, validator_set, BTreeMap::new())
+                        .advance(&chain)
+                        .unwrap();
+                    headers = true;
+                }
+                NodeMessage::Receipt(receipt) if addr == observer_client_addr => {
+                    assert!(matches!(
+                        receipt.result,
+                        TransactionResult::Unaccepted { .. }
+                    ));
+                    rejected = true;
+                }
+                _ => (),
+            }
+        }
+        observer.shutdown().unwrap();
+        // The last transactions commit on shutdown, their receipts still reach the client.
+        validator.shutdown().unwrap();
+        rt.block_on(handle.shutdown());
+    }
+
+    #[test]
+    fn websocket_test() {
+        let mut config = serde_yaml::from_str::<Config>(
+            "peer_listen_addr: 127.0.0.1:0\n\
+             client_listen_addr: 127.0.0.1:0\n\
+             websocket_listen_addr: 127.0.0.1:0\n\
+             websocket_max_connections_per_ip: 1\n\
+             minimum_view_timeout_ms: 100\n\
+             sync_request_limit: 10\n\
+             sync_response_timeout_ms: 100\n\
+             shutdown_timeout_ms: 5000\n",
+        )
+        .unwrap();
+        let keypair = crypto::generate_keypair();
+        config.genesis = Some(single_validator_genesis(keypair.public.to_bytes()));
+        config.my_keypair = Some(keypair);
+        let node = Node::start(config).unwrap();
+        let url = format!("ws://{}", node.websocket_addr().unwrap());
+
+        let rt = node.rt.clone();
+        let socket = rt.block_on(async {
+            let (mut socket, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            // One dashboard per address, the next is closed before the handshake.
+            assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+            // Blocks are only proposed with transactions, the first one commits once three
+            // more are on top of it.
+            let client_keypair = crypto::generate_keypair();
+            for n in 0..4 {
+                let request =
+                    NewTransactionRequest::new(&client_keypair, 1, TransactionData::App(vec![n]));
+                let submit = serde_json::json!({
+                    "type": "submit",
+                    "transaction": general_purpose::STANDARD.encode(request.try_to_vec().unwrap()),
+                });
+                socket
+                    .send(Message::Text(submit.to_string()))
+                    .await
+                    .unwrap();
+            }
+
+            // The receipt and the block carrying the transaction may come in any order,
+            // along with empty blocks and status updates.
+            let (mut committed, mut block, mut status) = (false, false, false);
+            while !(committed && block && status) {
+                let Some(Ok(Message::Text(text))) = socket.next().await else {
+                    panic!("socket closed");
+                };
+                let update: serde_json::Value = serde_json::from_str(&text).unwrap();
+                match update["type"].as_str().unwrap() {
+                    "receipt" => {
+                        assert_eq!(update["result"], "committed");
+                        committed = true;
+                    }
+                    "block" => {
+                        assert!(update["view"].is_u64());
+                        block |= update["tx_count"] == 1;
+                    }
+                    "status" => status = true,
+                    other => panic!("unexpected update {}", other),
+                }
+            }
+            socket
+        });
+        // The last transactions commit on shutdown, their receipts reach the dashboard still
+        // connected.
+        node.shutdown().unwrap();
+        drop(socket);
+    }
+}
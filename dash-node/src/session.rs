@@ -0,0 +1,161 @@
+use dash_common::{crypto::publickey_to_base64, TransactionReceipt};
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use hotstuff_rs::types::PublicKeyBytes;
+use log::{debug, warn};
+
+/// How long receipts of a disconnected client are kept, and how many of them.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub max_receipts: usize,
+    pub max_age: Duration,
+}
+
+struct Session {
+    /// Where the client was last seen, it may have disconnected since.
+    addr: Option<SocketAddr>,
+    queued: VecDeque<(Instant, TransactionReceipt)>,
+    last_active: Instant,
+}
+
+/// Clients by public key. Receipts of a client that is not connected are queued until it
+/// logs in again, possibly from another address.
+pub struct Sessions {
+    sessions: HashMap<PublicKeyBytes, Session>,
+    retention: Retention,
+}
+
+impl Sessions {
+    pub fn new(retention: Retention) -> Self {
+        Self {
+            sessions: Default::default(),
+            retention,
+        }
+    }
+
+    /// Binds the client to `addr`. Returns the receipts queued while it was away, to be
+    /// delivered there.
+    pub fn bind(&mut self, key: PublicKeyBytes, addr: SocketAddr) -> Vec<TransactionReceipt> {
+        let session = self.sessions.entry(key).or_insert_with(|| Session {
+            addr: None,
+            queued: Default::default(),
+            last_active: Instant::now(),
+        });
+        if session.addr != Some(addr) {
+            debug!("client {} is at {}", publickey_to_base64(key), addr);
+        }
+        session.addr = Some(addr);
+        session.last_active = Instant::now();
+        session
+            .queued
+            .drain(..)
+            .map(|(_, receipt)| receipt)
+            .collect()
+    }
+
+    pub fn addr(&self, key: &PublicKeyBytes) -> Option<SocketAddr> {
+        self.sessions.get(key).and_then(|session| session.addr)
+    }
+
+    /// Keeps a receipt until its requester logs in again. The oldest receipt is dropped when
+    /// the queue is full.
+    pub fn queue(&mut self, receipt: TransactionReceipt) {
+        let Some(session) = self.sessions.get_mut(&receipt.requester) else {
+            warn!(
+                "Receipt for unknown client {}, dropped",
+                publickey_to_base64(receipt.requester)
+            );
+            return;
+        };
+        if session.queued.len() >= self.retention.max_receipts {
+            warn!(
+                "Too many receipts queued for {}, dropping the oldest",
+                publickey_to_base64(receipt.requester)
+            );
+            session.queued.pop_front();
+        }
+        session.queued.push_back((Instant::now(), receipt));
+    }
+
+    /// Number of receipts waiting for their clients.
+    pub fn queued(&self) -> usize {
+        self.sessions
+            .values()
+            .map(|session| session.queued.len())
+            .sum()
+    }
+
+    /// Drops receipts older than the retention, and sessions that have been idle that long
+    /// with nothing queued and no live connection according to `connected`.
+    pub fn expire(&mut self, connected: impl Fn(SocketAddr) -> bool) {
+        let max_age = self.retention.max_age;
+        self.sessions.retain(|key, session| {
+            let before = session.queued.len();
+            session
+                .queued
+                .retain(|(queued_at, _)| queued_at.elapsed() < max_age);
+            if session.queued.len() < before {
+                warn!(
+                    "{} receipts for {} expired",
+                    before - session.queued.len(),
+                    publickey_to_base64(*key)
+                );
+            }
+            !session.queued.is_empty()
+                || session.last_active.elapsed() < max_age
+                || session.addr.is_some_and(&connected)
+        });
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::*;
+    use dash_common::{crypto::generate_keypair, TransactionResult};
+
+    fn receipt(requester: PublicKeyBytes, hash: u8) -> TransactionReceipt {
+        TransactionReceipt {
+            receiptor: [0; 32],
+            requester,
+            hash: [hash; 32],
+            result: TransactionResult::Commited,
+            signature: [0; 64],
+            proof: None,
+        }
+    }
+
+    #[test]
+    fn redelivery_test() {
+        let mut sessions = Sessions::new(Retention {
+            max_receipts: 2,
+            max_age: Duration::from_millis(100),
+        });
+        let key = generate_keypair().public.to_bytes();
+        let first: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        assert!(sessions.bind(key, first).is_empty());
+
+        // Queued while away, the oldest is dropped once the queue is full.
+        for hash in 0..3 {
+            sessions.queue(receipt(key, hash));
+        }
+        assert_eq!(sessions.queued(), 2);
+        let redelivered = sessions.bind(key, second);
+        let hashes: Vec<_> = redelivered.iter().map(|receipt| receipt.hash[0]).collect();
+        assert_eq!(hashes, vec![1, 2]);
+        assert_eq!(sessions.addr(&key), Some(second));
+        assert_eq!(sessions.queued(), 0);
+
+        // Receipts and idle sessions expire.
+        sessions.queue(receipt(key, 3));
+        std::thread::sleep(Duration::from_millis(150));
+        sessions.expire(|addr| addr == second);
+        assert_eq!(sessions.queued(), 0);
+        assert_eq!(sessions.addr(&key), Some(second));
+        sessions.expire(|_| false);
+        assert_eq!(sessions.addr(&key), None);
+    }
+}
@@ -0,0 +1,368 @@
+use crate::access::{AccessControl, AccessPolicy, RateLimit};
+use crate::kv_store::KVStoreImpl;
+use crate::network::NetworkImpl;
+use dash_common::{
+    crypto::publickey_to_base64,
+    subscription::{CommittedBlock, Subscription, Topic},
+    ClientMessage, NewTransactionRequest, NodeMessage, TransactionReceipt, TransactionResult,
+};
+use dash_network::{client::Client, typed::typed, Options};
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine};
+use borsh::BorshDeserialize;
+use futures::{SinkExt, StreamExt};
+use hotstuff_rs::{
+    replica::Replica,
+    types::{BlockHeight, ChainID, PublicKeyBytes, ViewNumber},
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    time,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// Period of the status pushed to every dashboard.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+/// Period of dropping the rate limits of quiet dashboards.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct WebSocketConfig {
+    pub chain_id: ChainID,
+    pub pubkey: PublicKeyBytes,
+    pub listen_addr: SocketAddr,
+    /// Client service of this node, every WebSocket is relayed to it over its own
+    /// connection.
+    pub client_addr: SocketAddr,
+    pub client_options: Options,
+    /// Sockets open at once, further dashboards are closed right away.
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// Transactions submitted from each dashboard address. The client service only sees
+    /// the relays, all from the same address.
+    pub ip_rate_limit: Option<RateLimit>,
+}
+
+/// What a dashboard sends.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    /// A borsh encoded, signed `NewTransactionRequest` in base64.
+    Submit { transaction: String },
+}
+
+/// What a dashboard is sent. Hashes and keys are base64.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Update {
+    Block {
+        height: BlockHeight,
+        hash: String,
+        tx_count: usize,
+        proposer: Option<String>,
+        view: Option<ViewNumber>,
+    },
+    Status {
+        chain_id: ChainID,
+        public_key: String,
+        committed_height: Option<BlockHeight>,
+        view: ViewNumber,
+        validators: usize,
+        connected_peers: usize,
+    },
+    Receipt {
+        hash: String,
+        requester: String,
+        receiptor: String,
+        result: &'static str,
+        /// Set when unaccepted.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        /// Set when busy.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+        /// Signature of the receiptor, see `TransactionReceipt::verify`.
+        signature: String,
+        /// Height and hash of the block, set when committed with a proof.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        height: Option<BlockHeight>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        block: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Update {
+    fn block(block: &CommittedBlock) -> Self {
+        Update::Block {
+            height: block.height,
+            hash: publickey_to_base64(block.hash),
+            tx_count: block.transactions.len(),
+            proposer: block.proposer.map(publickey_to_base64),
+            view: block.view,
+        }
+    }
+
+    fn receipt(receipt: &TransactionReceipt) -> Self {
+        Update::Receipt {
+            hash: publickey_to_base64(receipt.hash),
+            requester: publickey_to_base64(receipt.requester),
+            receiptor: publickey_to_base64(receipt.receiptor),
+            result: match receipt.result {
+                TransactionResult::Commited => "committed",
+                TransactionResult::Unaccepted { .. } => "unaccepted",
+                TransactionResult::Busy { .. } => "busy",
+            },
+            reason: match &receipt.result {
+                TransactionResult::Unaccepted { reason } => Some(reason.clone()),
+                _ => None,
+            },
+            retry_after_ms: match receipt.result {
+                TransactionResult::Busy { retry_after_ms } => Some(retry_after_ms),
+                _ => None,
+            },
+            signature: general_purpose::STANDARD.encode(receipt.signature),
+            height: receipt.proof.as_ref().map(|proof| proof.height()),
+            block: receipt
+                .proof
+                .as_ref()
+                .map(|proof| publickey_to_base64(proof.block_hash())),
+        }
+    }
+}
+
+/// JSON over WebSocket for browser dashboards: committed block summaries and the node
+/// status are pushed, transactions can be submitted and get their receipts. Each socket
+/// is relayed as an ordinary client of the client service, so transactions go through
+/// the same checks and commit path as any other.
+pub struct WebSocketGateway {
+    local_addr: SocketAddr,
+    gateway: Arc<Gateway>,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+    rt: Arc<Runtime>,
+}
+
+impl WebSocketGateway {
+    pub fn spawn(
+        config: WebSocketConfig,
+        replica: Arc<Replica<KVStoreImpl>>,
+        network: NetworkImpl,
+        rt: Arc<Runtime>,
+    ) -> Result<Self> {
+        let listener = rt.block_on(TcpListener::bind(config.listen_addr))?;
+        let local_addr = listener.local_addr()?;
+        info!("listening for dashboards on {}", local_addr);
+        let shutdown = CancellationToken::new();
+        let tasks = TaskTracker::new();
+        let access = AccessControl::new(ip_policy(config.ip_rate_limit));
+        let gateway = Arc::new(Gateway {
+            config,
+            connections: Default::default(),
+            access: Mutex::new(access),
+            replica,
+            network,
+            shutdown: shutdown.clone(),
+            tasks: tasks.clone(),
+        });
+        tasks.spawn_on(gateway.clone().accept(listener), rt.handle());
+        Ok(Self {
+            local_addr,
+            gateway,
+            shutdown,
+            tasks,
+            rt,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Applies a reloaded rate limit to the transactions submitted from now on.
+    pub fn update_ip_rate_limit(&self, limit: Option<RateLimit>) {
+        self.gateway.access.lock().unwrap().update(ip_policy(limit));
+    }
+
+    /// Closes every socket and waits for their relays to stop.
+    pub fn shutdown(self) {
+        self.shutdown.cancel();
+        self.tasks.close();
+        self.rt.block_on(self.tasks.wait());
+        info!("dashboard service shut down");
+    }
+}
+
+struct Gateway {
+    config: WebSocketConfig,
+    connections: Mutex<Connections>,
+    access: Mutex<AccessControl>,
+    replica: Arc<Replica<KVStoreImpl>>,
+    network: NetworkImpl,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Gateway {
+    async fn accept(self: Arc<Self>, listener: TcpListener) {
+        let mut expire = time::interval(EXPIRE_INTERVAL);
+        loop {
+            tokio::select! {
+                () = self.shutdown.cancelled() => return,
+                _ = expire.tick() => self.access.lock().unwrap().expire(),
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, addr)) => {
+                        if !self.connections.lock().unwrap().open(addr.ip(), &self.config) {
+                            // Dropping the stream closes it before the handshake.
+                            debug!("too many dashboards, {} turned down", addr);
+                            continue;
+                        }
+                        let gateway = self.clone();
+                        self.tasks.spawn(async move {
+                            if let Err(e) = gateway.serve(stream, addr).await {
+                                debug!("dashboard {} closed: {}", addr, e);
+                            }
+                            gateway.connections.lock().unwrap().close(addr.ip());
+                        });
+                    }
+                    Err(e) => warn!("Accept dashboard failed: {}", e),
+                },
+            }
+        }
+    }
+
+    async fn serve(&self, stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        let mut socket = tokio_tungstenite::accept_async(stream).await?;
+        debug!("dashboard connected from {}", addr);
+        let (channel, handle) = Client::spawn(self.config.client_options);
+        let (sender, mut receiver) = typed::<ClientMessage, NodeMessage>(channel);
+        let node = self.config.client_addr;
+        let subscription = ClientMessage::Subscribe(Subscription {
+            topic: Topic::Blocks,
+            start_height: None,
+        });
+        let mut status = time::interval(STATUS_INTERVAL);
+        let result: Result<()> = async {
+            sender.send(node, &subscription).await?;
+            loop {
+                let update = tokio::select! {
+                    () = self.shutdown.cancelled() => break,
+                    _ = status.tick() => self.status(),
+                    message = socket.next() => match message {
+                        Some(Ok(Message::Text(text))) => match self.request(addr.ip(), &text) {
+                            Ok(message) => {
+                                sender.send(node, &message).await?;
+                                continue;
+                            }
+                            Err(e) => Update::Error {
+                                message: e.to_string(),
+                            },
+                        },
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(e.into()),
+                    },
+                    Some((_, message)) = receiver.recv() => match message {
+                        Ok(NodeMessage::Block(block)) => Update::block(&block),
+                        Ok(NodeMessage::Receipt(receipt)) => Update::receipt(&receipt),
+                        Ok(_) => continue,
+                        Err(e) => {
+                            warn!("Malformed message from the client service: {}", e);
+                            continue;
+                        }
+                    },
+                };
+                socket
+                    .send(Message::Text(serde_json::to_string(&update)?))
+                    .await?;
+            }
+            socket.close(None).await?;
+            Ok(())
+        }
+        .await;
+        handle.shutdown().await;
+        result
+    }
+
+    fn request(&self, ip: IpAddr, text: &str) -> Result<ClientMessage> {
+        match serde_json::from_str(text)? {
+            Request::Submit { transaction } => {
+                self.access.lock().unwrap().admit_ip(ip)?;
+                let bytes = general_purpose::STANDARD.decode(transaction)?;
+                let request = NewTransactionRequest::try_from_slice(&bytes)
+                    .map_err(|e| anyhow!("malformed transaction: {}", e))?;
+                Ok(ClientMessage::Transaction(request))
+            }
+        }
+    }
+
+    fn status(&self) -> Update {
+        let snapshot = self.replica.block_tree_camera().snapshot();
+        Update::Status {
+            chain_id: self.config.chain_id,
+            public_key: publickey_to_base64(self.config.pubkey),
+            committed_height: snapshot
+                .highest_committed_block()
+                .and_then(|block| snapshot.block_height(&block)),
+            view: snapshot.highest_view_entered(),
+            validators: snapshot.committed_validator_set().len(),
+            connected_peers: self
+                .network
+                .peer_stats()
+                .values()
+                .filter(|stats| stats.connected)
+                .count(),
+        }
+    }
+}
+
+/// Sockets open in total and from each address.
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Connections {
+    /// Counts a new socket from `ip`, false if it is one too many.
+    fn open(&mut self, ip: IpAddr, config: &WebSocketConfig) -> bool {
+        let from_ip = self.per_ip.entry(ip).or_default();
+        if self.total >= config.max_connections || *from_ip >= config.max_connections_per_ip {
+            if *from_ip == 0 {
+                self.per_ip.remove(&ip);
+            }
+            return false;
+        }
+        *from_ip += 1;
+        self.total += 1;
+        true
+    }
+
+    fn close(&mut self, ip: IpAddr) {
+        self.total -= 1;
+        if let Some(from_ip) = self.per_ip.get_mut(&ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                self.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+fn ip_policy(per_ip: Option<RateLimit>) -> AccessPolicy {
+    AccessPolicy {
+        per_ip,
+        ..Default::default()
+    }
+}
@@ -1,14 +1,17 @@
-use dash_client::config::Config as ClientConfig;
+use dash_client::config::{Config as ClientConfig, ValidatorConfig};
 use dash_common::crypto;
-use dash_node::config::{Config, PeerConfig};
+use dash_node::config::{Config, PeerConfig, PeerTransport};
+use dash_node::genesis::Genesis;
 
+use std::collections::BTreeMap;
 use std::io::Write;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use std::{fs::OpenOptions, time::Duration};
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
+use hotstuff_rs::types::PublicKeyBytes;
 
 #[derive(Debug, Parser)]
 #[command(version, author, about = "Generate config/keypair files")]
@@ -22,12 +25,21 @@ struct Cli {
     /// Output path
     #[arg(short, long, default_value = "./")]
     pub output_path: PathBuf,
-    /// Start port
+    /// Start port, 0 picks free ephemeral ports, not allowed with --keypair
     #[arg(short, long, default_value = "3000")]
     pub start_port: u16,
     /// And generate config file for client
     #[arg(short, long, default_value = "true")]
     pub with_client_config: bool,
+    /// Transport of consensus traffic between nodes, tcp or quic
+    #[arg(short, long, default_value = "tcp")]
+    pub transport: PeerTransport,
+    /// Voting power of each node, comma separated, every node has power 1 when omitted
+    #[arg(short, long, value_delimiter = ',')]
+    pub powers: Vec<u64>,
+    /// Chain ID written to the genesis file
+    #[arg(long, default_value = "1")]
+    pub chain_id: u64,
 }
 
 fn main() -> Result<()> {
@@ -35,29 +47,73 @@ fn main() -> Result<()> {
     if !cli.output_path.is_dir() {
         return Err(anyhow!("output path is not a directory"));
     }
-    if cli.keypair {
-        (0..cli.count)
-            .map(|n| gen_keypair_file(cli.output_path.join(n.to_string())))
-            .collect::<Result<Vec<_>>>()?;
+    let powers = match cli.powers.len() {
+        0 => vec![1; cli.count as usize],
+        n if n == cli.count as usize => cli.powers.clone(),
+        n => return Err(anyhow!("{} powers given for {} nodes", n, cli.count)),
+    };
+    if powers.contains(&0) {
+        return Err(anyhow!("powers must be positive"));
+    }
+    // Keep the listeners until all files are written, so no port is handed out twice. Only
+    // node configs listen, keypair files merely name the ports in the client config.
+    let (_listeners, ports) = if cli.keypair {
+        (vec![], consecutive_ports(cli.start_port, cli.count)?)
     } else {
-        if cli.count as u32 * 2 + cli.start_port as u32 > u16::MAX as u32 {
-            return Err(anyhow!("port overflow"));
-        }
+        let listeners = reserve_ports(cli.start_port, cli.count)?;
+        let ports = listeners
+            .iter()
+            .map(|listener| Ok(listener.local_addr()?.port()))
+            .collect::<Result<Vec<_>>>()?;
+        (listeners, ports)
+    };
+    let validators = if cli.keypair {
         (0..cli.count)
-            .map(|n| gen_config_file(cli.output_path.join(n.to_string()), cli.start_port + n * 2))
+            .map(|n| {
+                Ok(ValidatorConfig {
+                    public_key: gen_keypair_file(cli.output_path.join(n.to_string()))?,
+                    power: powers[n as usize],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        let validators = ports
+            .chunks(2)
+            .enumerate()
+            .map(|(n, ports)| {
+                gen_config_file(
+                    cli.output_path.join(n.to_string()),
+                    ports[0],
+                    ports[1],
+                    cli.transport,
+                    powers[n],
+                )
+            })
             .collect::<Result<Vec<_>>>()?;
-    }
+        let client_validators = validators
+            .iter()
+            .map(|validator| ValidatorConfig {
+                public_key: validator.public_key,
+                power: validator.power,
+            })
+            .collect();
+        let genesis = Genesis::new(cli.chain_id, validators, BTreeMap::new());
+        gen_genesis_file(&cli.output_path, &genesis)?;
+        client_validators
+    };
 
     if cli.with_client_config {
         let config = ClientConfig {
-            node_addrs: (0..cli.count)
-                .map(|i| {
-                    let port = cli.start_port + i * 2 + 1;
-                    ("127.0.0.1:".to_string() + &port.to_string())
+            node_addrs: ports
+                .chunks(2)
+                .map(|ports| {
+                    ("127.0.0.1:".to_string() + &ports[1].to_string())
                         .parse::<SocketAddr>()
                         .unwrap()
                 })
                 .collect(),
+            chain_id: cli.chain_id,
+            validators,
             keypair: Some(crypto::generate_keypair()),
         };
         let mut client_config = OpenOptions::new()
@@ -76,7 +132,42 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn gen_config_file(mut path: PathBuf, port: u16) -> Result<()> {
+/// Binds a peer port and a client port for each node on localhost. With `start_port` 0 the
+/// ports are picked by the OS, otherwise they are consecutive and any port in use is an error.
+fn reserve_ports(start_port: u16, count: u16) -> Result<Vec<TcpListener>> {
+    if start_port == 0 {
+        return (0..count * 2)
+            .map(|_| Ok(TcpListener::bind("127.0.0.1:0")?))
+            .collect();
+    }
+    consecutive_ports(start_port, count)?
+        .into_iter()
+        .map(|port| {
+            TcpListener::bind(("127.0.0.1", port))
+                .map_err(|e| anyhow!("port {} is not available: {}", port, e))
+        })
+        .collect()
+}
+
+/// A peer port and a client port for each node, from `start_port` on.
+fn consecutive_ports(start_port: u16, count: u16) -> Result<Vec<u16>> {
+    // Nothing listens in keypair mode, an ephemeral port would not be known.
+    if start_port == 0 {
+        return Err(anyhow!("start port 0 cannot be used with --keypair"));
+    }
+    if count as u32 * 2 + start_port as u32 > u16::MAX as u32 {
+        return Err(anyhow!("port overflow"));
+    }
+    Ok((0..count * 2).map(|n| start_port + n).collect())
+}
+
+fn gen_config_file(
+    mut path: PathBuf,
+    port: u16,
+    client_port: u16,
+    transport: PeerTransport,
+    power: u64,
+) -> Result<PeerConfig> {
     let keypair = crypto::generate_keypair();
     let pubkey_bytes = keypair.public.to_bytes();
     let pem = crypto::keypair_to_pem(keypair);
@@ -91,32 +182,44 @@ fn gen_config_file(mut path: PathBuf, port: u16) -> Result<()> {
         .unwrap();
     seckey_file.write_all(pem.as_bytes()).unwrap();
 
-    path.set_file_name(name.clone() + ".peerconfig.yaml");
     let peer_config = PeerConfig {
         host_addr: ("127.0.0.1:".to_string() + &port.to_string()).parse()?,
         public_key: pubkey_bytes,
+        power,
     };
-    let peer_config_str = serde_yaml::to_string(&peer_config)?;
-    let mut peer_config_file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(&path)
-        .unwrap();
-    peer_config_file
-        .write_all(peer_config_str.as_bytes())
-        .unwrap();
 
     path.set_file_name(name + ".config.yaml");
     let config = Config {
-        validators: Default::default(),
-        peer_addresses: Default::default(),
+        genesis: None,
         my_keypair: None,
         peer_listen_addr: ("127.0.0.1:".to_string() + &port.to_string()).parse()?,
-        client_listen_addr: ("127.0.0.1:".to_string() + &(port + 1).to_string()).parse()?,
+        client_listen_addr: ("127.0.0.1:".to_string() + &client_port.to_string()).parse()?,
+        websocket_listen_addr: None,
+        websocket_max_connections: 256,
+        websocket_max_connections_per_ip: 8,
+        observer: false,
+        observer_allowlist: None,
         minimum_view_timeout: Duration::from_millis(500),
         sync_request_limit: 100,
         sync_response_timeout: Duration::from_millis(5000),
+        peer_transport: transport,
+        peer_max_frame_length: 8 * 1024 * 1024,
+        peer_max_frames_per_sec: 10_000,
+        client_max_frame_length: 64 * 1024,
+        client_max_send_frame_length: 8 * 1024 * 1024,
+        client_max_frames_per_sec: 1_000,
+        client_session_max_receipts: 1_000,
+        client_session_retention: Duration::from_millis(60_000),
+        client_pending_timeout: Duration::from_millis(120_000),
+        client_allowlist: None,
+        client_key_rate_limit: None,
+        client_ip_rate_limit: None,
+        client_max_in_flight: 100,
+        client_retry_after: Duration::from_millis(500),
+        heartbeat_interval: Duration::from_millis(1000),
+        max_missed_heartbeats: 3,
+        stats_log_interval: Duration::from_millis(10_000),
+        shutdown_timeout: Duration::from_millis(5000),
     };
     let config_str = serde_yaml::to_string(&config)?;
     let mut config_file = OpenOptions::new()
@@ -127,10 +230,20 @@ fn gen_config_file(mut path: PathBuf, port: u16) -> Result<()> {
         .unwrap();
     config_file.write_all(config_str.as_bytes()).unwrap();
 
+    Ok(peer_config)
+}
+
+fn gen_genesis_file(output_path: &Path, genesis: &Genesis) -> Result<()> {
+    let mut genesis_file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(output_path.join("genesis.yaml"))?;
+    genesis_file.write_all(serde_yaml::to_string(genesis)?.as_bytes())?;
     Ok(())
 }
 
-fn gen_keypair_file(mut path: PathBuf) -> Result<()> {
+fn gen_keypair_file(mut path: PathBuf) -> Result<PublicKeyBytes> {
     let keypair = crypto::generate_keypair();
     let pubkey_bytes = keypair.public.to_bytes();
     let pem = crypto::keypair_to_pem(keypair);
@@ -155,5 +268,5 @@ fn gen_keypair_file(mut path: PathBuf) -> Result<()> {
         .unwrap();
     pubkey_file.write_all(pk_b64.as_bytes()).unwrap();
 
-    Ok(())
+    Ok(pubkey_bytes)
 }
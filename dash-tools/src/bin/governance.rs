@@ -0,0 +1,162 @@
+use dash_client::network::Network;
+use dash_common::{
+    crypto,
+    governance::{GovernanceTransaction, ValidatorChange},
+    NewTransactionRequest, TransactionData, TransactionResult,
+};
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
+use clap::{Parser, Subcommand};
+use tokio::time::{self, Instant};
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    author,
+    about = "Propose, sign and submit validator set changes"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Write an unsigned governance transaction
+    Propose {
+        /// Chain ID of the nodes
+        #[arg(long, default_value = "1")]
+        chain_id: u64,
+        /// Number of governance transactions applied so far
+        #[arg(short, long)]
+        nonce: u64,
+        /// Validator to add or re-weight, as <base64 public key>=<power>
+        #[arg(short, long)]
+        put: Vec<String>,
+        /// Validator to remove, as a base64 public key
+        #[arg(short, long)]
+        remove: Vec<String>,
+        /// Transaction file
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Add the signature of a validator to a transaction file
+    Sign {
+        /// Keypair of the validator
+        #[arg(short, long)]
+        keypair: PathBuf,
+        /// Transaction file
+        transaction: PathBuf,
+    },
+    /// Submit a transaction to a node and wait for it to commit
+    Submit {
+        /// Client address of the node
+        #[arg(short, long)]
+        node: SocketAddr,
+        /// How long to wait for the receipt, in seconds
+        #[arg(short, long, default_value = "30")]
+        timeout: u64,
+        /// Transaction file
+        transaction: PathBuf,
+    },
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Propose {
+            chain_id,
+            nonce,
+            put,
+            remove,
+            output,
+        } => {
+            let mut changes = put
+                .iter()
+                .map(|put| {
+                    let (key, power) = put
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("expected <public key>=<power>, got {}", put))?;
+                    Ok(ValidatorChange::Put {
+                        public_key: crypto::publickey_from_base64(key)?,
+                        power: power.parse()?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for key in remove {
+                changes.push(ValidatorChange::Remove {
+                    public_key: crypto::publickey_from_base64(&key)?,
+                });
+            }
+            let transaction = GovernanceTransaction::new(chain_id, nonce, changes);
+            fs::write(output, transaction.try_to_vec()?)?;
+        }
+        Command::Sign {
+            keypair,
+            transaction: path,
+        } => {
+            let keypair = crypto::keypair_from_pem(&fs::read_to_string(keypair)?)?;
+            let mut transaction = GovernanceTransaction::try_from_slice(&fs::read(&path)?)?;
+            transaction.sign(&keypair);
+            fs::write(path, transaction.try_to_vec()?)?;
+        }
+        Command::Submit {
+            node,
+            timeout,
+            transaction,
+        } => {
+            let transaction = GovernanceTransaction::try_from_slice(&fs::read(transaction)?)?;
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(submit(node, transaction, Duration::from_secs(timeout)))?;
+        }
+    }
+    Ok(())
+}
+
+async fn submit(
+    node: SocketAddr,
+    transaction: GovernanceTransaction,
+    timeout: Duration,
+) -> Result<()> {
+    // The transaction is authorized by the validators' signatures, any requester key does.
+    let keypair = crypto::generate_keypair();
+    let chain_id = transaction.chain_id;
+    let request =
+        NewTransactionRequest::new(&keypair, chain_id, TransactionData::Governance(transaction));
+    let hash = request.hash;
+    let mut network = Network::new(vec![node], chain_id, keypair)?;
+    network.send_transaction(request).await?;
+    let deadline = Instant::now() + timeout;
+    let result = loop {
+        if let Some(receipt) = network.receive_transaction_receipt().await? {
+            if receipt.hash == hash {
+                match receipt.verify(chain_id) {
+                    Ok(()) => break Ok(receipt.result),
+                    Err(e) => eprintln!("receipt dropped: {}", e),
+                }
+            }
+        }
+        if Instant::now() > deadline {
+            break Err(anyhow!("no receipt within {:?}", timeout));
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    };
+    network.shutdown().await;
+    match result? {
+        TransactionResult::Commited => {
+            println!("committed");
+            Ok(())
+        }
+        TransactionResult::Unaccepted { reason } => Err(anyhow!("not accepted: {}", reason)),
+        TransactionResult::Busy { retry_after_ms } => {
+            Err(anyhow!("node busy, submit again in {} ms", retry_after_ms))
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use dash_client::network::Network;
+use dash_common::{
+    crypto,
+    subscription::{Subscription, Topic},
+    NodeMessage,
+};
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TopicArg {
+    Blocks,
+    Requester,
+    Events,
+}
+
+#[derive(Debug, Parser)]
+#[command(version, author, about = "Follow the committed chain through a node")]
+struct Cli {
+    /// Client address of the node
+    #[arg(short, long)]
+    node: SocketAddr,
+    /// Chain ID of the node
+    #[arg(long, default_value = "1")]
+    chain_id: u64,
+    /// What to follow
+    #[arg(short, long, value_enum, default_value = "blocks")]
+    topic: TopicArg,
+    /// Base64 public key of the requester, for the requester topic
+    #[arg(short, long, required_if_eq("topic", "requester"))]
+    requester: Option<String>,
+    /// Replay committed blocks from this height on before following new ones
+    #[arg(short, long)]
+    start_height: Option<u64>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let topic = match cli.topic {
+        TopicArg::Blocks => Topic::Blocks,
+        TopicArg::Requester => Topic::Requester(crypto::publickey_from_base64(
+            cli.requester.as_deref().unwrap_or_default(),
+        )?),
+        TopicArg::Events => Topic::Events,
+    };
+    let subscription = Subscription {
+        topic,
+        start_height: cli.start_height,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(follow(cli.node, cli.chain_id, subscription))
+}
+
+async fn follow(node: SocketAddr, chain_id: u64, subscription: Subscription) -> Result<()> {
+    // Feeds are public, any key does for the login.
+    let mut network = Network::new(vec![node], chain_id, crypto::generate_keypair())?;
+    network.subscribe(subscription).await?;
+    while let Some(message) = network.receive_notification().await {
+        match message {
+            NodeMessage::Block(block) => println!(
+                "block {} {} with {} transactions",
+                block.height,
+                crypto::publickey_to_base64(block.hash),
+                block.transactions.len()
+            ),
+            NodeMessage::Transaction(transaction) => println!(
+                "transaction {} at height {}",
+                crypto::publickey_to_base64(transaction.request.hash),
+                transaction.height
+            ),
+            NodeMessage::Event(event) => {
+                println!("event at height {}: {:?}", event.height, event.event)
+            }
+            NodeMessage::Receipt(_) | NodeMessage::Headers(_) => {}
+        }
+    }
+    network.shutdown().await;
+    Ok(())
+}
@@ -15,20 +15,14 @@ cargo run -p dash-tools --bin=config-gen
 
 for i in {0..3}
 do
-    mkdir -p ${i}/config/peers
+    mkdir -p ${i}/config
     mv $i.config.yaml ${i}/config/config.yaml
     mv $i.sec ${i}/config/sec_key
-    for j in {0..3}
-    do
-        cp ${j}.peerconfig.yaml ${i}/config/peers/${j}.peerconfig.yaml
-    done
+    cp genesis.yaml ${i}/config/genesis.yaml
     cp ${WORKSPACE_FOLDER}/target/debug/dash-node ${i}/
 done
 
-for i in {0..3}
-do
-    rm ${i}.peerconfig.yaml
-done
+rm genesis.yaml
 
 mkdir -p client/config
 mv client.config.yaml client/config/config.yaml